@@ -0,0 +1,57 @@
+use std::{
+    env,
+    net::{Shutdown, SocketAddrV4},
+    process,
+};
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use toytcp::{
+    async_net::{TcpConnection, TcpListener},
+    net::default_stack,
+};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    std::env::set_var("RUST_LOG", "debug");
+    env_logger::init();
+
+    let args = env::args().collect::<Vec<_>>();
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} <LOCAL IP> <LOCAL PORT>", args[0]);
+        process::exit(1);
+    }
+
+    let local_address = SocketAddrV4::new(args[1].parse()?, args[2].parse()?);
+
+    // Refuse the connections to the other ports of this host as echoserver does.
+    default_stack().set_reset_unowned_ports(true);
+
+    let listener = TcpListener::bind(local_address)?;
+
+    loop {
+        let (connection, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let socket_id = connection.socket_id();
+            if let Err(e) = echo(connection).await {
+                eprintln!("{} : {:?}", socket_id, e);
+            }
+        });
+    }
+}
+
+/// Send back everything received on the connection.
+async fn echo(mut connection: TcpConnection) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+
+    loop {
+        let received_size = connection.read(&mut buffer).await?;
+        // The client has closed the connection.
+        if received_size == 0 {
+            return Ok(connection.shutdown(Shutdown::Write)?);
+        }
+        connection.write_all(&buffer[..received_size]).await?;
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, net::Ipv4Addr, process};
+use std::{env, io, net::Ipv4Addr, process};
 
 use anyhow::Result;
 
@@ -19,7 +19,25 @@ fn main() -> Result<()> {
     let remote_port = args[2].parse::<u16>()?;
 
     let client = TcpStream::new();
-    client.connect(remote_address, remote_port)?;
+    // Reset the segments to the ports of this host that are not in use, e.g. the ones of a previous
+    // run, as the kernel would if its RSTs were not dropped in the test network.
+    client.set_reset_unowned_ports(true);
+    let socket = client.connect(remote_address, remote_port)?;
+
+    // Send each line read from stdin and print the echoed reply.
+    for line in io::stdin().lines() {
+        let line = line? + "\n";
+        client.send(socket, line.as_bytes())?;
+
+        let mut buffer = vec![0; line.len()];
+        let mut received_size = 0;
+        while received_size < line.len() {
+            received_size += client.recv(socket, &mut buffer[received_size..])?;
+        }
+        print!("{}", String::from_utf8_lossy(&buffer[..received_size]));
+    }
+
+    client.close(socket)?;
 
     Ok(())
 }
@@ -1,8 +1,8 @@
-use std::{env, net::Ipv4Addr, process};
+use std::{env, net::Ipv4Addr, process, sync::Arc, thread};
 
 use anyhow::Result;
 
-use toytcp::tcp::TcpStream;
+use toytcp::{socket::TcpSocketId, tcp::TcpStream};
 
 fn main() -> Result<()> {
     std::env::set_var("RUST_LOG", "debug");
@@ -19,9 +19,32 @@ fn main() -> Result<()> {
     let local_port = args[2].parse::<u16>()?;
 
     let server = TcpStream::new();
+    // The kernel's RSTs are dropped in the test network and no one else uses TCP on this host, so
+    // the connections to the other ports are refused by this stack instead.
+    server.set_reset_unowned_ports(true);
     let listning_socket = server.listen(local_address, local_port)?;
 
     loop {
-        let _connected_socket = server.accept(listning_socket)?;
+        let connected_socket = server.accept(listning_socket)?;
+        let cloned_server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = echo(&cloned_server, connected_socket) {
+                eprintln!("{} : {:?}", connected_socket, e);
+            }
+        });
+    }
+}
+
+/// Send back everything received on the connected socket.
+fn echo(server: &TcpStream, connected_socket: TcpSocketId) -> toytcp::Result<()> {
+    let mut buffer = [0; 1024];
+
+    loop {
+        let received_size = server.recv(connected_socket, &mut buffer)?;
+        // The client has closed the connection.
+        if received_size == 0 {
+            return server.close(connected_socket);
+        }
+        server.send(connected_socket, &buffer[..received_size])?;
     }
 }
@@ -42,9 +42,12 @@ sudo ip netns exec host1 ip route add 0.0.0.0/0 via 10.0.0.254
 sudo ip netns exec host2 ip route add 0.0.0.0/0 via 10.0.1.254
 sudo ip netns exec router sysctl -w net.ipv4.ip_forward=1
 
-# drop RST
-sudo ip netns exec host1 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
-sudo ip netns exec host2 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
+# drop RST sent by the kernel, which knows nothing about the connections of toytcp.
+# RST sent by toytcp comes from a raw socket owned by root, so it is let through.
+# the examples answer the segments to the ports not in use with a RST instead of the kernel
+# (TcpStream::set_reset_unowned_ports), since no one else uses TCP on these hosts.
+sudo ip netns exec host1 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -m owner ! --uid-owner 0 -j DROP
+sudo ip netns exec host2 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -m owner ! --uid-owner 0 -j DROP
 
 # turn off checksum offloading
 sudo ip netns exec host2 sudo ethtool -K host2-veth1 tx off
@@ -0,0 +1,186 @@
+use std::{
+    future::poll_fn,
+    io,
+    net::{Shutdown, SocketAddrV4},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    error::Result, net::default_stack, socket::TcpSocketId, tcp::TcpStream, wait_queue::WaitQueue,
+};
+
+/// An async listening socket for tokio.
+/// Tasks are woken up by the receiving thread of the stack instead of blocking a worker thread.
+/// The listening socket is closed when this is dropped.
+pub struct TcpListener {
+    tcp: Arc<TcpStream>,
+    socket_id: TcpSocketId,
+    wait_queue: Arc<WaitQueue>,
+}
+
+impl TcpListener {
+    /// Listen on the address with the default stack.
+    pub fn bind(address: SocketAddrV4) -> Result<Self> {
+        Self::bind_with(default_stack(), address)
+    }
+
+    /// Listen on the address with the specified stack.
+    pub fn bind_with(tcp: &Arc<TcpStream>, address: SocketAddrV4) -> Result<Self> {
+        let socket_id = tcp.listen(*address.ip(), address.port())?;
+        let wait_queue = tcp.wait_queue(socket_id)?;
+        Ok(Self {
+            tcp: Arc::clone(tcp),
+            socket_id,
+            wait_queue,
+        })
+    }
+
+    /// Wait until a connection is established and return it with the remote address.
+    pub async fn accept(&self) -> Result<(TcpConnection, SocketAddrV4)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    /// Poll for a connection established on the listening socket.
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Result<(TcpConnection, SocketAddrV4)>> {
+        let (socket_id, wait_queue) = loop {
+            let socket_id =
+                ready!(self
+                    .tcp
+                    .poll_until(self.socket_id, &self.wait_queue, cx, |socket| {
+                        TcpStream::dequeue_connection(self.socket_id, socket)
+                    }))?;
+            if let Some(wait_queue) = self.tcp.accepted(socket_id)? {
+                break (socket_id, wait_queue);
+            }
+        };
+        let connection = TcpConnection {
+            tcp: Arc::clone(&self.tcp),
+            socket_id,
+            wait_queue,
+        };
+        let peer_address = connection.peer_addr();
+        Poll::Ready(Ok((connection, peer_address)))
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.socket_id.local_addr()
+    }
+
+    pub fn socket_id(&self) -> TcpSocketId {
+        self.socket_id
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // Nothing can be done about the error here.
+        let _ = self.tcp.close_on(self.socket_id, Some(&self.wait_queue));
+    }
+}
+
+/// An async connection for tokio, implementing AsyncRead and AsyncWrite.
+/// Dropping the connection sends a FIN without waiting for the connection termination to
+/// complete.
+pub struct TcpConnection {
+    tcp: Arc<TcpStream>,
+    socket_id: TcpSocketId,
+    wait_queue: Arc<WaitQueue>,
+}
+
+impl TcpConnection {
+    /// Connect to the address with the default stack.
+    pub async fn connect(address: SocketAddrV4) -> Result<Self> {
+        Self::connect_with(default_stack(), address).await
+    }
+
+    /// Connect to the address with the specified stack.
+    pub async fn connect_with(tcp: &Arc<TcpStream>, address: SocketAddrV4) -> Result<Self> {
+        let (socket_id, wait_queue) = tcp.start_connect(*address.ip(), address.port())?;
+        poll_fn(|cx| {
+            tcp.poll_until(socket_id, &wait_queue, cx, |socket| {
+                TcpStream::is_connected(socket_id, socket)
+            })
+        })
+        .await?;
+        Ok(Self {
+            tcp: Arc::clone(tcp),
+            socket_id,
+            wait_queue,
+        })
+    }
+
+    /// Shut down the read, write, or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.tcp
+            .shutdown_on(self.socket_id, Some(&self.wait_queue), how)
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.socket_id.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.socket_id.peer_addr()
+    }
+
+    pub fn socket_id(&self) -> TcpSocketId {
+        self.socket_id
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        // Dropping the connection cannot wait for the termination in a task, so the socket is
+        // left to be deleted in the background as net::TcpConnection does.
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl AsyncRead for TcpConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let socket_id = self.socket_id;
+        let received_size =
+            ready!(self
+                .tcp
+                .poll_until(socket_id, &self.wait_queue, cx, |socket| {
+                    TcpStream::receive_data(socket_id, socket, buffer.initialize_unfilled())
+                }))?;
+        buffer.advance(received_size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TcpConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let socket_id = self.socket_id;
+        let sent_size = ready!(self
+            .tcp
+            .poll_until(socket_id, &self.wait_queue, cx, |socket| {
+                TcpStream::send_segments(socket_id, socket, buffer)
+            }))?;
+        Poll::Ready(Ok(sent_size))
+    }
+
+    /// poll_write() is ready only once the segments have been handed to the raw socket, so
+    /// nothing is buffered here.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Send a FIN without waiting for the connection termination to complete.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(self.shutdown(Shutdown::Write)?))
+    }
+}
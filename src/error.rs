@@ -0,0 +1,108 @@
+use std::{io, net::Ipv4Addr, sync::PoisonError};
+
+use crate::socket::{TcpSocketId, TcpState};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors returned by the TCP stack.
+#[derive(Debug)]
+pub enum Error {
+    /// The remote host answered the SYN with a RST.
+    ConnectionRefused(TcpSocketId),
+    /// The remote host has reset the connection.
+    ConnectionReset(TcpSocketId),
+    /// The connection has been aborted by abort().
+    ConnectionAborted(TcpSocketId),
+    /// The connection has been terminated and the socket has been deleted.
+    ConnectionClosed(TcpSocketId),
+    /// The SYN or SYN|ACK has not been acknowledged after the maximum number of retransmissions.
+    TimedOut(TcpSocketId),
+    /// A socket is already listening on the address and port.
+    AddrInUse(Ipv4Addr, u16),
+    /// No local port is available for a new connection.
+    AddrNotAvailable,
+    NoSuchSocket(TcpSocketId),
+    /// connect_timeout(), accept_timeout(), or recv() or send() with a read or write timeout has
+    /// given up waiting. The socket is left as it was, except that the connection is aborted by
+    /// connect_timeout().
+    WaitTimedOut(TcpSocketId),
+    /// The socket is non-blocking and the operation would have to wait.
+    WouldBlock(TcpSocketId),
+    /// The operation is not allowed in the current state of the socket.
+    InvalidState(TcpSocketId, TcpState),
+    /// A thread panicked while holding a lock of the stack.
+    LockPoisoned,
+    /// A thread of the stack has stopped, so no more segments are processed.
+    StackFailed(String),
+    Io(io::Error),
+}
+
+impl Error {
+    /// The corresponding kind of std::io::Error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::ConnectionRefused(_) => io::ErrorKind::ConnectionRefused,
+            Self::ConnectionReset(_) => io::ErrorKind::ConnectionReset,
+            Self::ConnectionAborted(_) => io::ErrorKind::ConnectionAborted,
+            Self::ConnectionClosed(_) | Self::InvalidState(_, _) => io::ErrorKind::NotConnected,
+            Self::TimedOut(_) | Self::WaitTimedOut(_) => io::ErrorKind::TimedOut,
+            Self::WouldBlock(_) => io::ErrorKind::WouldBlock,
+            Self::AddrInUse(_, _) => io::ErrorKind::AddrInUse,
+            Self::AddrNotAvailable => io::ErrorKind::AddrNotAvailable,
+            Self::NoSuchSocket(_) => io::ErrorKind::NotFound,
+            Self::LockPoisoned | Self::StackFailed(_) => io::ErrorKind::Other,
+            Self::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectionRefused(id) => write!(f, "{} : Connection refused", id),
+            Self::ConnectionReset(id) => write!(f, "{} : Connection reset", id),
+            Self::ConnectionAborted(id) => write!(f, "{} : Connection aborted", id),
+            Self::ConnectionClosed(id) => write!(f, "{} : Connection closed", id),
+            Self::TimedOut(id) => write!(f, "{} : Connection timed out", id),
+            Self::WaitTimedOut(id) => write!(f, "{} : Operation timed out", id),
+            Self::WouldBlock(id) => write!(f, "{} : Operation would block", id),
+            Self::AddrInUse(address, port) => write!(f, "{}:{} : Address in use", address, port),
+            Self::AddrNotAvailable => write!(f, "No available local port"),
+            Self::NoSuchSocket(id) => write!(f, "No such socket {}", id),
+            Self::InvalidState(id, state) => write!(f, "{} : Invalid in state {:?}", id, state),
+            Self::LockPoisoned => write!(f, "Lock poisoned"),
+            Self::StackFailed(reason) => write!(f, "TCP stack failed: {}", reason),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Self::LockPoisoned
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
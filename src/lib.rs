@@ -1,3 +1,14 @@
+#[cfg(feature = "tokio")]
+pub mod async_net;
+pub mod error;
+pub mod net;
 pub mod packet;
+pub mod poll;
+pub mod retransmission;
+pub mod sack;
+pub mod seq;
 pub mod socket;
 pub mod tcp;
+pub mod wait_queue;
+
+pub use error::{Error, Result};
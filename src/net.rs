@@ -0,0 +1,240 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddrV4},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{error::Result, socket::TcpSocketId, tcp::TcpStream, wait_queue::WaitQueue};
+
+static DEFAULT_STACK: OnceLock<Arc<TcpStream>> = OnceLock::new();
+
+/// The stack used by TcpListener::bind() and TcpConnection::connect().
+/// It is created on first use and lives until the process exits.
+pub fn default_stack() -> &'static Arc<TcpStream> {
+    DEFAULT_STACK.get_or_init(TcpStream::new)
+}
+
+/// A listening socket, mirroring std::net::TcpListener.
+/// The listening socket is closed when this is dropped.
+pub struct TcpListener {
+    tcp: Arc<TcpStream>,
+    socket_id: TcpSocketId,
+}
+
+impl TcpListener {
+    /// Listen on the address with the default stack.
+    pub fn bind(address: SocketAddrV4) -> Result<Self> {
+        Self::bind_with(default_stack(), address)
+    }
+
+    /// Listen on the address with the specified stack.
+    pub fn bind_with(tcp: &Arc<TcpStream>, address: SocketAddrV4) -> Result<Self> {
+        let socket_id = tcp.listen(*address.ip(), address.port())?;
+        Ok(Self {
+            tcp: Arc::clone(tcp),
+            socket_id,
+        })
+    }
+
+    /// Block until a connection is established and return it with the remote address.
+    pub fn accept(&self) -> Result<(TcpConnection, SocketAddrV4)> {
+        self.accept_until(None)
+    }
+
+    /// Accept a connection in the same way as accept(), but give up when the timeout elapses.
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<(TcpConnection, SocketAddrV4)> {
+        self.accept_until(Some(Instant::now() + timeout))
+    }
+
+    fn accept_until(&self, deadline: Option<Instant>) -> Result<(TcpConnection, SocketAddrV4)> {
+        let (socket_id, wait_queue) = self.tcp.accept_until(self.socket_id, deadline)?;
+        let connection = TcpConnection {
+            tcp: Arc::clone(&self.tcp),
+            socket_id,
+            wait_queue,
+        };
+        let peer_address = connection.peer_addr();
+        Ok((connection, peer_address))
+    }
+
+    /// Make accept() return WouldBlock instead of waiting, or blocking again.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp.set_nonblocking(self.socket_id, nonblocking)
+    }
+
+    /// An iterator over the connections accepted on the listening socket. It never returns None.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.socket_id.local_addr()
+    }
+
+    pub fn socket_id(&self) -> TcpSocketId {
+        self.socket_id
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        // Nothing can be done about the error here.
+        let _ = self.tcp.close(self.socket_id);
+    }
+}
+
+/// An iterator that infinitely accepts connections on a TcpListener.
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<TcpConnection>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(connection, _)| connection))
+    }
+}
+
+/// A connection, mirroring std::net::TcpStream.
+/// Dropping the connection sends a FIN without waiting for the connection termination to
+/// complete. Use close() to wait for it.
+pub struct TcpConnection {
+    tcp: Arc<TcpStream>,
+    socket_id: TcpSocketId,
+    // Identifies the incarnation of the connection, since the four-tuple can be reused by a new
+    // one once the socket has been deleted.
+    wait_queue: Arc<WaitQueue>,
+}
+
+impl TcpConnection {
+    /// Connect to the address with the default stack.
+    pub fn connect(address: SocketAddrV4) -> Result<Self> {
+        Self::connect_with(default_stack(), address)
+    }
+
+    /// Connect to the address with the specified stack.
+    pub fn connect_with(tcp: &Arc<TcpStream>, address: SocketAddrV4) -> Result<Self> {
+        Self::connect_until(tcp, address, None)
+    }
+
+    /// Connect to the address with the default stack, giving up when the timeout elapses.
+    pub fn connect_timeout(address: SocketAddrV4, timeout: Duration) -> Result<Self> {
+        Self::connect_timeout_with(default_stack(), address, timeout)
+    }
+
+    /// Connect to the address with the specified stack, giving up when the timeout elapses.
+    pub fn connect_timeout_with(
+        tcp: &Arc<TcpStream>,
+        address: SocketAddrV4,
+        timeout: Duration,
+    ) -> Result<Self> {
+        Self::connect_until(tcp, address, Some(Instant::now() + timeout))
+    }
+
+    fn connect_until(
+        tcp: &Arc<TcpStream>,
+        address: SocketAddrV4,
+        deadline: Option<Instant>,
+    ) -> Result<Self> {
+        let (socket_id, wait_queue) = tcp.connect_until(*address.ip(), address.port(), deadline)?;
+        Ok(Self {
+            tcp: Arc::clone(tcp),
+            socket_id,
+            wait_queue,
+        })
+    }
+
+    /// Close the connection and block until the connection termination is completed.
+    pub fn close(self) -> Result<()> {
+        self.tcp.close_on(self.socket_id, Some(&self.wait_queue))
+    }
+
+    /// Shut down the read, write, or both halves of the connection.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.tcp
+            .shutdown_on(self.socket_id, Some(&self.wait_queue), how)
+    }
+
+    /// Make reads and writes return WouldBlock instead of waiting, or blocking again.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp.set_nonblocking(self.socket_id, nonblocking)
+    }
+
+    /// Set how long reads wait for data. None means forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.tcp.set_read_timeout(self.socket_id, timeout)
+    }
+
+    /// Set how long writes wait for the send window to open. None means forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.tcp.set_write_timeout(self.socket_id, timeout)
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.tcp.read_timeout(self.socket_id)
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.tcp.write_timeout(self.socket_id)
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.socket_id.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.socket_id.peer_addr()
+    }
+
+    pub fn socket_id(&self) -> TcpSocketId {
+        self.socket_id
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        // The socket has already been deleted if the connection has been closed, reset or aborted.
+        // Otherwise it is deleted once the remote host closes its side, or by the FIN-WAIT-2
+        // timeout if it never does.
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for &TcpConnection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Ok(self
+            .tcp
+            .recv_on(self.socket_id, Some(&self.wait_queue), buffer)?)
+    }
+}
+
+impl Write for &TcpConnection {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        Ok(self
+            .tcp
+            .send_on(self.socket_id, Some(&self.wait_queue), buffer)?)
+    }
+
+    /// Data is transmitted as soon as it is written, so there is nothing to flush.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for TcpConnection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buffer)
+    }
+}
+
+impl Write for TcpConnection {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (&*self).write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -5,9 +5,14 @@ use pnet::{
     util,
 };
 
-// No Options field for simplicity.
+use crate::seq::SeqNum;
+
+/// The size of the TCP header without options.
 const TCP_HEADER_SIZE: usize = 20;
 
+/// The maximum size of the options, limited by the 4-bit data offset.
+const TCP_MAX_OPTIONS_SIZE: usize = 40;
+
 /// Control bits.
 #[allow(non_snake_case)]
 pub mod TcpFlags {
@@ -25,6 +30,150 @@ pub mod TcpFlags {
     pub const FIN: u8 = 0b00_0001;
 }
 
+/// Option kinds.
+#[allow(non_snake_case)]
+pub mod TcpOptionKind {
+    /// End of Option List.
+    pub const EOL: u8 = 0;
+    /// No-Operation.
+    pub const NOP: u8 = 1;
+    /// Maximum Segment Size. ref. RFC 9293
+    pub const MSS: u8 = 2;
+    /// Window Scale. ref. RFC 7323
+    pub const WINDOW_SCALE: u8 = 3;
+    /// SACK-Permitted. ref. RFC 2018
+    pub const SACK_PERMITTED: u8 = 4;
+    /// SACK. ref. RFC 2018
+    pub const SACK: u8 = 5;
+    /// Timestamps. ref. RFC 7323
+    pub const TIMESTAMPS: u8 = 8;
+}
+
+/// TCP option.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TcpOption {
+    Eol,
+    Nop,
+    /// The maximum segment size the sender can receive.
+    Mss(u16),
+    /// The shift count applied to the window field by the sender.
+    WindowScale(u8),
+    SackPermitted,
+    /// Blocks of data received out of order, each given by its left and right edges.
+    Sack(Vec<(SeqNum, SeqNum)>),
+    /// TSval and TSecr.
+    Timestamps(u32, u32),
+    /// An option not supported. Ignored on receipt.
+    Unknown(u8, Vec<u8>),
+}
+
+impl TcpOption {
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::Eol => TcpOptionKind::EOL,
+            Self::Nop => TcpOptionKind::NOP,
+            Self::Mss(_) => TcpOptionKind::MSS,
+            Self::WindowScale(_) => TcpOptionKind::WINDOW_SCALE,
+            Self::SackPermitted => TcpOptionKind::SACK_PERMITTED,
+            Self::Sack(_) => TcpOptionKind::SACK,
+            Self::Timestamps(_, _) => TcpOptionKind::TIMESTAMPS,
+            Self::Unknown(kind, _) => *kind,
+        }
+    }
+
+    /// The number of octets the option occupies, including the kind and length octets.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Eol | Self::Nop => 1,
+            Self::Mss(_) => 4,
+            Self::WindowScale(_) => 3,
+            Self::SackPermitted => 2,
+            Self::Sack(blocks) => 2 + 8 * blocks.len(),
+            Self::Timestamps(_, _) => 10,
+            Self::Unknown(_, data) => 2 + data.len(),
+        }
+    }
+
+    /// Append the option to the buffer in the wire format.
+    pub fn serialize(&self, buffer: &mut Vec<u8>) {
+        buffer.push(self.kind());
+        if matches!(self, Self::Eol | Self::Nop) {
+            return;
+        }
+        buffer.push(self.size() as u8);
+
+        match self {
+            Self::Eol | Self::Nop | Self::SackPermitted => {}
+            Self::Mss(mss) => buffer.extend_from_slice(&mss.to_be_bytes()),
+            Self::WindowScale(shift_count) => buffer.push(*shift_count),
+            Self::Sack(blocks) => {
+                for (left_edge, right_edge) in blocks {
+                    buffer.extend_from_slice(&left_edge.get().to_be_bytes());
+                    buffer.extend_from_slice(&right_edge.get().to_be_bytes());
+                }
+            }
+            Self::Timestamps(value, echo_reply) => {
+                buffer.extend_from_slice(&value.to_be_bytes());
+                buffer.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+            Self::Unknown(_, data) => buffer.extend_from_slice(data),
+        }
+    }
+
+    /// Parse the options field. Parsing stops at the end of option list or at a malformed option,
+    /// ignoring the rest. The end of option list and no-operations are not returned.
+    pub fn parse(mut bytes: &[u8]) -> Vec<Self> {
+        let mut options = Vec::new();
+
+        while let Some(&kind) = bytes.first() {
+            match kind {
+                TcpOptionKind::EOL => break,
+                TcpOptionKind::NOP => {
+                    bytes = &bytes[1..];
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(&length) = bytes.get(1) else {break;};
+            let length = length as usize;
+            if length < 2 || length > bytes.len() {
+                break;
+            }
+            let data = &bytes[2..length];
+            bytes = &bytes[length..];
+
+            let option = match (kind, data.len()) {
+                (TcpOptionKind::MSS, 2) => Self::Mss(u16::from_be_bytes([data[0], data[1]])),
+                (TcpOptionKind::WINDOW_SCALE, 1) => Self::WindowScale(data[0]),
+                (TcpOptionKind::SACK_PERMITTED, 0) => Self::SackPermitted,
+                (TcpOptionKind::SACK, n) if n > 0 && n % 8 == 0 => Self::Sack(
+                    data.chunks_exact(8)
+                        .map(|block| {
+                            (
+                                SeqNum::new(u32::from_be_bytes([
+                                    block[0], block[1], block[2], block[3],
+                                ])),
+                                SeqNum::new(u32::from_be_bytes([
+                                    block[4], block[5], block[6], block[7],
+                                ])),
+                            )
+                        })
+                        .collect(),
+                ),
+                (TcpOptionKind::TIMESTAMPS, 8) => Self::Timestamps(
+                    u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                    u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                ),
+                _ => Self::Unknown(kind, data.to_vec()),
+            };
+            options.push(option);
+        }
+
+        options
+    }
+}
+
 /// TCP packet.
 #[derive(Debug)]
 pub struct TcpPacket {
@@ -32,10 +181,22 @@ pub struct TcpPacket {
 }
 
 impl TcpPacket {
-    pub fn new(payload_size: usize) -> Self {
-        Self {
-            buffer: vec![0; TCP_HEADER_SIZE + payload_size],
+    /// Create a packet with the options and room for the payload. The options are padded with
+    /// zeros to a multiple of 4 octets, and the data offset is set accordingly.
+    pub fn new(options: &[TcpOption], payload_size: usize) -> Self {
+        let mut buffer = vec![0; TCP_HEADER_SIZE];
+        for option in options {
+            option.serialize(&mut buffer);
         }
+        assert!(buffer.len() - TCP_HEADER_SIZE <= TCP_MAX_OPTIONS_SIZE);
+        // Zero is the end of option list.
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+        let header_length = buffer.len();
+        buffer.resize(header_length + payload_size, 0);
+
+        let mut packet = Self { buffer };
+        packet.set_data_offset((header_length / 4) as u8);
+        packet
     }
 
     // Getters
@@ -47,22 +208,22 @@ impl TcpPacket {
         u16::from_be_bytes([self.buffer[2], self.buffer[3]])
     }
 
-    pub fn sequence_number(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn sequence_number(&self) -> SeqNum {
+        SeqNum::new(u32::from_be_bytes([
             self.buffer[4],
             self.buffer[5],
             self.buffer[6],
             self.buffer[7],
-        ])
+        ]))
     }
 
-    pub fn acknowledgment_number(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn acknowledgment_number(&self) -> SeqNum {
+        SeqNum::new(u32::from_be_bytes([
             self.buffer[8],
             self.buffer[9],
             self.buffer[10],
             self.buffer[11],
-        ])
+        ]))
     }
 
     pub fn data_offset(&self) -> u8 {
@@ -85,6 +246,46 @@ impl TcpPacket {
         u16::from_be_bytes([self.buffer[18], self.buffer[19]])
     }
 
+    /// The length of the header including the options, given by the data offset.
+    pub fn header_length(&self) -> usize {
+        self.data_offset() as usize * 4
+    }
+
+    pub fn options(&self) -> Vec<TcpOption> {
+        TcpOption::parse(&self.buffer[TCP_HEADER_SIZE..self.header_length()])
+    }
+
+    /// TSval and TSecr of the Timestamps option, if any.
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.options().into_iter().find_map(|option| match option {
+            TcpOption::Timestamps(value, echo_reply) => Some((value, echo_reply)),
+            _ => None,
+        })
+    }
+
+    /// The blocks of the SACK option. Empty if there is none.
+    pub fn sack_blocks(&self) -> Vec<(SeqNum, SeqNum)> {
+        self.options()
+            .into_iter()
+            .find_map(|option| match option {
+                TcpOption::Sack(blocks) => Some(blocks),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// SEG.LEN, the number of octets occupied by the segment, counting SYN and FIN.
+    pub fn segment_length(&self) -> u32 {
+        let mut length = self.payload().len() as u32;
+        if self.flags() & TcpFlags::SYN != 0 {
+            length += 1;
+        }
+        if self.flags() & TcpFlags::FIN != 0 {
+            length += 1;
+        }
+        length
+    }
+
     // Setters
     pub fn set_source_port(&mut self, source_port: u16) {
         self.buffer[0..2].copy_from_slice(&source_port.to_be_bytes());
@@ -94,16 +295,16 @@ impl TcpPacket {
         self.buffer[2..4].copy_from_slice(&destination_port.to_be_bytes());
     }
 
-    pub fn set_sequence_number(&mut self, sequence_number: u32) {
-        self.buffer[4..8].copy_from_slice(&sequence_number.to_be_bytes());
+    pub fn set_sequence_number(&mut self, sequence_number: SeqNum) {
+        self.buffer[4..8].copy_from_slice(&sequence_number.get().to_be_bytes());
     }
 
-    pub fn set_acknowledgment_number(&mut self, acknowledgment_number: u32) {
-        self.buffer[8..12].copy_from_slice(&acknowledgment_number.to_be_bytes());
+    pub fn set_acknowledgment_number(&mut self, acknowledgment_number: SeqNum) {
+        self.buffer[8..12].copy_from_slice(&acknowledgment_number.get().to_be_bytes());
     }
 
     pub fn set_data_offset(&mut self, data_offset: u8) {
-        self.buffer[12] |= data_offset << 4;
+        self.buffer[12] = (self.buffer[12] & 0x0f) | (data_offset << 4);
     }
 
     pub fn set_flags(&mut self, flags: u8) {
@@ -123,21 +324,23 @@ impl TcpPacket {
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
-        assert_eq!(TCP_HEADER_SIZE + payload.len(), self.buffer.len());
-        self.buffer[TCP_HEADER_SIZE..].copy_from_slice(payload);
+        let header_length = self.header_length();
+        assert_eq!(header_length + payload.len(), self.buffer.len());
+        self.buffer[header_length..].copy_from_slice(payload);
     }
 
-    /// Verify checksum of the TCP packet.
+    /// Verify the data offset and checksum of the TCP packet.
     pub fn verify_packet(&self, local_address: Ipv4Addr, remote_address: Ipv4Addr) -> bool {
-        self.checksum()
-            == util::ipv4_checksum(
-                self.packet(),
-                8,
-                &[],
-                &local_address,
-                &remote_address,
-                IpNextHeaderProtocols::Tcp,
-            )
+        (TCP_HEADER_SIZE..=self.buffer.len()).contains(&self.header_length())
+            && self.checksum()
+                == util::ipv4_checksum(
+                    self.packet(),
+                    8,
+                    &[],
+                    &local_address,
+                    &remote_address,
+                    IpNextHeaderProtocols::Tcp,
+                )
     }
 }
 
@@ -147,7 +350,7 @@ impl pnet::packet::Packet for TcpPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_length()..]
     }
 }
 
@@ -158,3 +361,242 @@ impl<'a> From<PnetTcpPakcet<'a>> for TcpPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(options: &[TcpOption]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for option in options {
+            option.serialize(&mut buffer);
+        }
+        buffer
+    }
+
+    /// A packet with the checksum calculated for the addresses.
+    fn packet_with_checksum(mut packet: TcpPacket) -> TcpPacket {
+        packet.set_checksum(util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &Ipv4Addr::new(10, 0, 0, 1),
+            &Ipv4Addr::new(10, 0, 1, 1),
+            IpNextHeaderProtocols::Tcp,
+        ));
+        packet
+    }
+
+    #[test]
+    fn round_trip_every_option() {
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::WindowScale(14),
+            TcpOption::SackPermitted,
+            TcpOption::Sack(vec![(SeqNum::new(1), SeqNum::new(u32::MAX))]),
+            TcpOption::Sack(
+                (0..4)
+                    .map(|i| (SeqNum::new(i * 100), SeqNum::new(i * 100 + 50)))
+                    .collect(),
+            ),
+            TcpOption::Timestamps(0x0102_0304, 0xfffe_fdfc),
+            TcpOption::Unknown(30, vec![1, 2, 3]),
+            TcpOption::Unknown(253, Vec::new()),
+        ];
+        for option in options {
+            let bytes = serialize(std::slice::from_ref(&option));
+            assert_eq!(bytes.len(), option.size(), "{:?}", option);
+            assert_eq!(bytes[0], option.kind(), "{:?}", option);
+            assert_eq!(bytes[1] as usize, option.size(), "{:?}", option);
+            assert_eq!(TcpOption::parse(&bytes), vec![option]);
+        }
+    }
+
+    #[test]
+    fn serialize_in_wire_format() {
+        assert_eq!(serialize(&[TcpOption::Eol]), [0]);
+        assert_eq!(serialize(&[TcpOption::Nop]), [1]);
+        assert_eq!(serialize(&[TcpOption::Mss(1460)]), [2, 4, 0x05, 0xb4]);
+        assert_eq!(serialize(&[TcpOption::WindowScale(7)]), [3, 3, 7]);
+        assert_eq!(serialize(&[TcpOption::SackPermitted]), [4, 2]);
+        assert_eq!(
+            serialize(&[TcpOption::Sack(vec![(SeqNum::new(1), SeqNum::new(2))])]),
+            [5, 10, 0, 0, 0, 1, 0, 0, 0, 2]
+        );
+        assert_eq!(
+            serialize(&[TcpOption::Timestamps(1, 2)]),
+            [8, 10, 0, 0, 0, 1, 0, 0, 0, 2]
+        );
+    }
+
+    #[test]
+    fn parse_skips_no_operations() {
+        let options = [
+            TcpOption::Nop,
+            TcpOption::Nop,
+            TcpOption::Timestamps(1, 2),
+            TcpOption::Nop,
+            TcpOption::WindowScale(3),
+        ];
+        assert_eq!(
+            TcpOption::parse(&serialize(&options)),
+            [TcpOption::Timestamps(1, 2), TcpOption::WindowScale(3)]
+        );
+    }
+
+    #[test]
+    fn parse_stops_at_end_of_option_list() {
+        let options = [TcpOption::Mss(536), TcpOption::Eol, TcpOption::Mss(1460)];
+        assert_eq!(
+            TcpOption::parse(&serialize(&options)),
+            [TcpOption::Mss(536)]
+        );
+        assert_eq!(TcpOption::parse(&[]), []);
+    }
+
+    #[test]
+    fn parse_stops_at_length_less_than_2() {
+        for length in [0, 1] {
+            let mut bytes = serialize(&[TcpOption::Mss(1460)]);
+            bytes.extend_from_slice(&[TcpOptionKind::WINDOW_SCALE, length, 7]);
+            bytes.extend_from_slice(&serialize(&[TcpOption::SackPermitted]));
+            assert_eq!(
+                TcpOption::parse(&bytes),
+                [TcpOption::Mss(1460)],
+                "length = {}",
+                length
+            );
+        }
+    }
+
+    #[test]
+    fn parse_stops_at_length_past_end() {
+        let mut bytes = serialize(&[TcpOption::SackPermitted]);
+        bytes.extend_from_slice(&[TcpOptionKind::TIMESTAMPS, 10, 0, 0, 0, 1]);
+        assert_eq!(TcpOption::parse(&bytes), [TcpOption::SackPermitted]);
+
+        // The length octet itself is missing.
+        let mut bytes = serialize(&[TcpOption::SackPermitted]);
+        bytes.push(TcpOptionKind::MSS);
+        assert_eq!(TcpOption::parse(&bytes), [TcpOption::SackPermitted]);
+    }
+
+    #[test]
+    fn parse_sack_not_multiple_of_8_as_unknown() {
+        for data_length in [0, 7, 9, 15] {
+            let mut bytes = vec![TcpOptionKind::SACK, 2 + data_length as u8];
+            bytes.extend((0..data_length).map(|i| i as u8));
+            bytes.extend_from_slice(&serialize(&[TcpOption::WindowScale(1)]));
+            assert_eq!(
+                TcpOption::parse(&bytes),
+                [
+                    TcpOption::Unknown(TcpOptionKind::SACK, bytes[2..2 + data_length].to_vec()),
+                    TcpOption::WindowScale(1)
+                ],
+                "data length = {}",
+                data_length
+            );
+        }
+    }
+
+    #[test]
+    fn parse_wrong_length_as_unknown() {
+        let bytes = [
+            [TcpOptionKind::MSS, 3, 5].as_slice(),
+            &[TcpOptionKind::WINDOW_SCALE, 4, 1, 2],
+            &[TcpOptionKind::SACK_PERMITTED, 3, 0],
+            &[TcpOptionKind::TIMESTAMPS, 6, 0, 0, 0, 1],
+        ]
+        .concat();
+        assert_eq!(
+            TcpOption::parse(&bytes),
+            [
+                TcpOption::Unknown(TcpOptionKind::MSS, vec![5]),
+                TcpOption::Unknown(TcpOptionKind::WINDOW_SCALE, vec![1, 2]),
+                TcpOption::Unknown(TcpOptionKind::SACK_PERMITTED, vec![0]),
+                TcpOption::Unknown(TcpOptionKind::TIMESTAMPS, vec![0, 0, 0, 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_pads_options_and_sets_data_offset() {
+        let packet = TcpPacket::new(&[], 0);
+        assert_eq!(packet.data_offset(), 5);
+        assert_eq!(packet.header_length(), TCP_HEADER_SIZE);
+        assert_eq!(packet.options(), []);
+
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::Nop,
+            TcpOption::WindowScale(7),
+            TcpOption::SackPermitted,
+        ];
+        // 4 + 1 + 3 + 2 = 10 octets padded to 12.
+        let packet = TcpPacket::new(&options, 3);
+        assert_eq!(packet.data_offset(), 8);
+        assert_eq!(packet.header_length(), TCP_HEADER_SIZE + 12);
+        assert_eq!(packet.packet().len(), TCP_HEADER_SIZE + 12 + 3);
+        assert_eq!(
+            &packet.packet()[TCP_HEADER_SIZE + 10..TCP_HEADER_SIZE + 12],
+            [0, 0]
+        );
+        assert_eq!(
+            packet.options(),
+            [
+                TcpOption::Mss(1460),
+                TcpOption::WindowScale(7),
+                TcpOption::SackPermitted
+            ]
+        );
+    }
+
+    #[test]
+    fn payload_starts_at_data_offset() {
+        let options = [TcpOption::Nop, TcpOption::Nop, TcpOption::Timestamps(5, 6)];
+        let mut packet = TcpPacket::new(&options, 4);
+        packet.set_flags(TcpFlags::ACK | TcpFlags::FIN);
+        packet.set_payload(b"data");
+        assert_eq!(packet.payload(), b"data");
+        assert_eq!(packet.segment_length(), 5);
+        assert_eq!(packet.timestamps(), Some((5, 6)));
+        assert_eq!(packet.sack_blocks(), []);
+
+        // A received packet is parsed by its data offset, too.
+        let packet = TcpPacket::from(PnetTcpPakcet::new(packet.packet()).unwrap());
+        assert_eq!(packet.header_length(), TCP_HEADER_SIZE + 12);
+        assert_eq!(packet.payload(), b"data");
+        assert_eq!(packet.timestamps(), Some((5, 6)));
+
+        // The octets beyond the data offset are not options.
+        let mut packet = TcpPacket::new(&[TcpOption::Mss(1460)], 0);
+        packet.set_data_offset(5);
+        assert_eq!(packet.options(), []);
+        assert_eq!(packet.payload(), [2, 4, 0x05, 0xb4]);
+    }
+
+    #[test]
+    fn verify_data_offset() {
+        let local_address = Ipv4Addr::new(10, 0, 1, 1);
+        let remote_address = Ipv4Addr::new(10, 0, 0, 1);
+
+        let packet = packet_with_checksum(TcpPacket::new(&[TcpOption::Mss(1460)], 2));
+        assert!(packet.verify_packet(local_address, remote_address));
+
+        // Less than the header without options, or past the end of the packet.
+        for data_offset in [0, 4, 8, 15] {
+            let mut packet = TcpPacket::new(&[TcpOption::Mss(1460)], 2);
+            packet.set_data_offset(data_offset);
+            let packet = packet_with_checksum(packet);
+            assert!(
+                !packet.verify_packet(local_address, remote_address),
+                "data offset = {}",
+                data_offset
+            );
+        }
+
+        let mut packet = packet_with_checksum(TcpPacket::new(&[], 0));
+        packet.set_window_size(1);
+        assert!(!packet.verify_packet(local_address, remote_address));
+    }
+}
@@ -0,0 +1,171 @@
+use std::ops::BitOr;
+
+use crate::socket::{TcpSocket, TcpSocketId, TcpState};
+
+/// The operations a user is interested in, combined with `|`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Interest(u8);
+
+impl Interest {
+    /// recv() can be called without blocking.
+    pub const READABLE: Self = Self(0b001);
+    /// send() can be called without blocking.
+    pub const WRITABLE: Self = Self(0b010);
+    /// accept() can be called on the listening socket without blocking.
+    pub const ACCEPTABLE: Self = Self(0b100);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & Self::READABLE.0 != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & Self::WRITABLE.0 != 0
+    }
+
+    pub fn is_acceptable(self) -> bool {
+        self.0 & Self::ACCEPTABLE.0 != 0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// The readiness of a socket reported by TcpStream::poll().
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Event {
+    socket_id: TcpSocketId,
+    readable: bool,
+    writable: bool,
+    acceptable: bool,
+    hup: bool,
+}
+
+impl Event {
+    /// Get the readiness of the socket. None means the socket has been deleted.
+    pub(crate) fn of(socket_id: TcpSocketId, socket: Option<&TcpSocket>) -> Self {
+        // Every operation on a deleted socket returns immediately with the result of the
+        // connection termination.
+        let Some(socket) = socket else {
+            return Self {
+                socket_id,
+                readable: true,
+                writable: true,
+                acceptable: true,
+                hup: true,
+            };
+        };
+
+        // The remote host has sent a FIN.
+        let peer_closed = matches!(
+            socket.state,
+            TcpState::CloseWait | TcpState::Closing | TcpState::LastAck | TcpState::TimeWait
+        );
+
+        Self {
+            socket_id,
+            readable: !socket.receive_buffer.is_empty() || socket.read_shutdown || peer_closed,
+            // send() returns an error without blocking unless the connection is being established
+            // or the send window is full.
+            writable: match socket.state {
+                TcpState::Established | TcpState::CloseWait => socket.usable_window() > 0,
+                TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => false,
+                _ => true,
+            },
+            acceptable: socket.state == TcpState::Listen
+                && !socket.connected_socket_queue.is_empty(),
+            hup: peer_closed,
+        }
+    }
+
+    /// Keep only the readiness the user is interested in. Returns None if nothing is left.
+    pub(crate) fn filter(self, interest: Interest) -> Option<Self> {
+        let event = Self {
+            readable: self.readable && interest.is_readable(),
+            writable: self.writable && interest.is_writable(),
+            acceptable: self.acceptable && interest.is_acceptable(),
+            ..self
+        };
+        (event.readable || event.writable || event.acceptable || event.hup).then_some(event)
+    }
+
+    pub fn socket_id(&self) -> TcpSocketId {
+        self.socket_id
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn is_acceptable(&self) -> bool {
+        self.acceptable
+    }
+
+    /// The remote host has closed the connection, or the socket has been deleted.
+    pub fn is_hup(&self) -> bool {
+        self.hup
+    }
+}
+
+/// A buffer of events filled by TcpStream::poll().
+#[derive(Debug)]
+pub struct Events {
+    events: Vec<Event>,
+    capacity: usize,
+}
+
+impl Events {
+    /// Create a buffer holding up to the number of events per poll.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            events: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Event> {
+        self.events.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// Add the event. Returns false if the buffer is full.
+    pub(crate) fn push(&mut self, event: Event) -> bool {
+        if self.events.len() >= self.capacity {
+            return false;
+        }
+        self.events.push(event);
+        true
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = std::slice::Iter<'a, Event>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.events.iter()
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use crate::{packet::TcpFlags, seq::SeqNum};
+
+/// Clock granularity. This is also the interval at which the timer thread checks the timers.
+pub const CLOCK_GRANULARITY: Duration = Duration::from_millis(10);
+
+/// RTO before any RTT measurement is made. RFC 6298 (2.1).
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// Lower bound of the RTO. RFC 6298 (2.4).
+const MIN_RTO: Duration = Duration::from_secs(1);
+/// Upper bound of the RTO. RFC 6298 (2.5). The interval between window probes is also bounded by
+/// it.
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// A segment that occupies sequence space and has not been acknowledged yet.
+#[derive(Debug)]
+pub struct RetransmissionQueueEntry {
+    pub sequence_number: SeqNum,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+    /// When the segment was transmitted last.
+    pub transmitted_at: Instant,
+    /// How many times the segment has been transmitted, including the first transmission.
+    pub transmission_count: usize,
+    /// Whether the remote host has reported the segment in a SACK block. ref. RFC 6675 3
+    pub sacked: bool,
+}
+
+impl RetransmissionQueueEntry {
+    pub fn new(sequence_number: SeqNum, flags: u8, payload: &[u8]) -> Self {
+        Self {
+            sequence_number,
+            flags,
+            payload: payload.to_vec(),
+            transmitted_at: Instant::now(),
+            transmission_count: 1,
+            sacked: false,
+        }
+    }
+
+    /// Length of the sequence space occupied by the segment. SYN and FIN count as one octet each.
+    pub fn segment_length(&self) -> u32 {
+        let mut length = self.payload.len() as u32;
+        if self.flags & TcpFlags::SYN != 0 {
+            length += 1;
+        }
+        if self.flags & TcpFlags::FIN != 0 {
+            length += 1;
+        }
+        length
+    }
+
+    /// The sequence number following the last octet of the segment.
+    pub fn end(&self) -> SeqNum {
+        self.sequence_number + self.segment_length()
+    }
+}
+
+/// Retransmission timer with the RTO computed from RTT samples as described in RFC 6298.
+#[derive(Debug)]
+pub struct RetransmissionTimer {
+    /// Smoothed round-trip time. None until the first RTT measurement.
+    srtt: Option<Duration>,
+    /// Round-trip time variation.
+    rttvar: Duration,
+    /// Retransmission timeout.
+    rto: Duration,
+    /// When the timer expires. None if the timer is not running.
+    expires_at: Option<Instant>,
+}
+
+impl RetransmissionTimer {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+            expires_at: None,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Update SRTT, RTTVAR and RTO with a new RTT measurement. RFC 6298 (2.2) and (2.3).
+    pub fn update(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|, beta = 1/4
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                // SRTT <- (1 - alpha) * SRTT + alpha * R', alpha = 1/8
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+
+        // RTO <- SRTT + max (G, K*RTTVAR), K = 4
+        let rto = self.srtt.unwrap() + CLOCK_GRANULARITY.max(self.rttvar * 4);
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Double the RTO after the timer expires. RFC 6298 (5.5).
+    pub fn back_off(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    /// (Re)start the timer so that it expires after RTO.
+    pub fn start(&mut self) {
+        self.expires_at = Some(Instant::now() + self.rto);
+    }
+
+    pub fn stop(&mut self) {
+        self.expires_at = None;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.expires_at.is_some()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl Default for RetransmissionTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+
+use crate::{retransmission::RetransmissionQueueEntry, seq::SeqNum};
+
+/// DupThresh, the number of segments SACKed above an unSACKed one that makes it deemed lost.
+/// ref. RFC 6675 2
+pub const DUP_THRESH: usize = 3;
+
+/// Data received out of order, kept until the gap before it is filled.
+#[derive(Debug, Default)]
+pub struct ReassemblyQueue {
+    /// Blocks of contiguous data, each with its first sequence number. Overlapping and adjacent
+    /// blocks are merged. The most recently updated block comes first, which is the order in which
+    /// they are reported in the SACK option. ref. RFC 2018 4
+    blocks: Vec<(SeqNum, Vec<u8>)>,
+    /// The sequence number of a FIN received out of order.
+    fin: Option<SeqNum>,
+}
+
+impl ReassemblyQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.fin.is_none()
+    }
+
+    /// Add the data starting at the sequence number, followed by a FIN if fin is true.
+    pub fn insert(&mut self, sequence_number: SeqNum, data: &[u8], fin: bool) {
+        if fin {
+            self.fin = Some(sequence_number + data.len() as u32);
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        let mut start = sequence_number;
+        let mut end = sequence_number + data.len() as u32;
+        let mut merged = Vec::new();
+        let mut i = 0;
+        while i < self.blocks.len() {
+            let (block_start, block_end) = Self::edges(&self.blocks[i]);
+            if block_start <= end && start <= block_end {
+                if block_start < start {
+                    start = block_start;
+                }
+                if end < block_end {
+                    end = block_end;
+                }
+                merged.push(self.blocks.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut buffer = vec![0; (end - start) as usize];
+        for (block_start, block) in merged {
+            let offset = (block_start - start) as usize;
+            buffer[offset..offset + block.len()].copy_from_slice(&block);
+        }
+        let offset = (sequence_number - start) as usize;
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+        self.blocks.insert(0, (start, buffer));
+    }
+
+    /// Remove the data following the sequence number, RCV.NXT after the gap has been filled.
+    /// Returns the data and whether a FIN follows it. Blocks before the sequence number are
+    /// discarded since they have been received again in order.
+    pub fn pop(&mut self, sequence_number: SeqNum) -> (Vec<u8>, bool) {
+        self.blocks
+            .retain(|block| sequence_number < Self::edges(block).1);
+
+        let mut data = Vec::new();
+        if let Some(i) = self
+            .blocks
+            .iter()
+            .position(|(block_start, _)| *block_start <= sequence_number)
+        {
+            let (block_start, block) = self.blocks.remove(i);
+            data = block[(sequence_number - block_start) as usize..].to_vec();
+        }
+
+        let fin = self.fin == Some(sequence_number + data.len() as u32);
+        if fin {
+            self.fin = None;
+        }
+        (data, fin)
+    }
+
+    /// The left and right edges of up to the specified number of blocks, the most recently
+    /// updated first.
+    pub fn sack_blocks(&self, count: usize) -> Vec<(SeqNum, SeqNum)> {
+        self.blocks.iter().take(count).map(Self::edges).collect()
+    }
+
+    fn edges((start, data): &(SeqNum, Vec<u8>)) -> (SeqNum, SeqNum) {
+        (*start, *start + data.len() as u32)
+    }
+}
+
+/// The state of the SACK-based loss recovery. ref. RFC 6675
+#[derive(Debug, Default)]
+pub struct LossRecovery {
+    /// Whether the loss recovery is in progress.
+    pub in_recovery: bool,
+    /// DupAcks, the number of duplicate ACKs carrying new SACK information since SND.UNA last
+    /// advanced.
+    pub dup_acks: usize,
+    /// RecoveryPoint, SND.NXT when the last loss recovery started. The loss recovery ends when it
+    /// is acknowledged, and no new one starts until then.
+    pub recovery_point: Option<SeqNum>,
+    /// HighRxt, the end of the last segment retransmitted by the loss recovery.
+    pub high_rxt: SeqNum,
+    /// RescueRxt, RecoveryPoint when the rescue retransmission was made.
+    pub rescue_rxt: Option<SeqNum>,
+    /// The congestion window during the loss recovery, half the data in flight when it started.
+    /// The stack has no congestion control otherwise, so only the send window limits the data
+    /// sent outside the loss recovery.
+    pub cwnd: usize,
+}
+
+impl LossRecovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// IsLost() of every segment in the retransmission queue. A segment is deemed lost when
+/// DupThresh segments, or more than (DupThresh - 1) * SMSS bytes, above it have been SACKed.
+/// ref. RFC 6675 4
+pub fn lost_segments(
+    retransmission_queue: &VecDeque<RetransmissionQueueEntry>,
+    send_mss: usize,
+) -> Vec<bool> {
+    let mut lost = vec![false; retransmission_queue.len()];
+    let mut sacked_segments = 0;
+    let mut sacked_bytes = 0;
+    for (i, entry) in retransmission_queue.iter().enumerate().rev() {
+        lost[i] = sacked_segments >= DUP_THRESH || sacked_bytes > (DUP_THRESH - 1) * send_mss;
+        if entry.sacked {
+            sacked_segments += 1;
+            sacked_bytes += entry.segment_length() as usize;
+        }
+    }
+    lost
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom, Rng};
+
+    use super::*;
+    use crate::packet::TcpFlags;
+
+    /// Receive a segment trimmed to start at RCV.NXT. The data in order is appended to the stream
+    /// followed by the data reassembled after it, and the data out of order is kept in the queue.
+    fn receive(
+        queue: &mut ReassemblyQueue,
+        stream: &mut Vec<u8>,
+        next: &mut SeqNum,
+        sequence_number: SeqNum,
+        data: &[u8],
+    ) {
+        let end = sequence_number + data.len() as u32;
+        if *next < sequence_number {
+            queue.insert(sequence_number, data, false);
+        } else if *next < end {
+            stream.extend_from_slice(&data[(*next - sequence_number) as usize..]);
+            *next = end;
+            let (data, _) = queue.pop(*next);
+            stream.extend_from_slice(&data);
+            *next += data.len() as u32;
+        }
+    }
+
+    #[test]
+    fn pop_after_gap_is_filled() {
+        let mut queue = ReassemblyQueue::new();
+        assert!(queue.is_empty());
+
+        queue.insert(SeqNum::new(110), b"world", false);
+        assert!(!queue.is_empty());
+        // Nothing follows RCV.NXT until the gap is filled.
+        assert_eq!(queue.pop(SeqNum::new(100)), (Vec::new(), false));
+        assert_eq!(queue.sack_blocks(4), [(SeqNum::new(110), SeqNum::new(115))]);
+
+        assert_eq!(queue.pop(SeqNum::new(110)), (b"world".to_vec(), false));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_from_middle_of_block() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(SeqNum::new(110), b"0123456789", false);
+
+        // The first half has been received again in order.
+        assert_eq!(queue.pop(SeqNum::new(115)), (b"56789".to_vec(), false));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_discards_blocks_received_again_in_order() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(SeqNum::new(110), b"abc", false);
+        queue.insert(SeqNum::new(120), b"def", false);
+
+        assert_eq!(queue.pop(SeqNum::new(118)), (Vec::new(), false));
+        assert_eq!(queue.sack_blocks(4), [(SeqNum::new(120), SeqNum::new(123))]);
+    }
+
+    #[test]
+    fn merge_overlapping_and_adjacent_blocks() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(SeqNum::new(110), b"bbbb", false);
+        queue.insert(SeqNum::new(120), b"dddd", false);
+        // Adjacent to the first and overlapping the second.
+        queue.insert(SeqNum::new(114), b"cccccccc", false);
+        assert_eq!(queue.sack_blocks(4), [(SeqNum::new(110), SeqNum::new(124))]);
+
+        // Covering the whole block.
+        queue.insert(SeqNum::new(108), b"aaaaaaaaaaaaaaaaa", false);
+        assert_eq!(queue.sack_blocks(4), [(SeqNum::new(108), SeqNum::new(125))]);
+        assert_eq!(
+            queue.pop(SeqNum::new(108)),
+            (b"aaaaaaaaaaaaaaaaa".to_vec(), false)
+        );
+    }
+
+    #[test]
+    fn sack_blocks_most_recent_first() {
+        let mut queue = ReassemblyQueue::new();
+        for sequence_number in [200, 300, 400, 500, 600] {
+            queue.insert(SeqNum::new(sequence_number), b"x", false);
+        }
+        assert_eq!(
+            queue.sack_blocks(3),
+            [
+                (SeqNum::new(600), SeqNum::new(601)),
+                (SeqNum::new(500), SeqNum::new(501)),
+                (SeqNum::new(400), SeqNum::new(401)),
+            ]
+        );
+
+        // An updated block is reported first. ref. RFC 2018 4
+        queue.insert(SeqNum::new(301), b"y", false);
+        assert_eq!(
+            queue.sack_blocks(2),
+            [
+                (SeqNum::new(300), SeqNum::new(302)),
+                (SeqNum::new(600), SeqNum::new(601)),
+            ]
+        );
+    }
+
+    #[test]
+    fn fin_out_of_order() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(SeqNum::new(110), b"bye", true);
+
+        assert_eq!(queue.pop(SeqNum::new(100)), (Vec::new(), false));
+        assert_eq!(queue.pop(SeqNum::new(110)), (b"bye".to_vec(), true));
+        assert!(queue.is_empty());
+
+        // A FIN without data is not reported in the SACK blocks.
+        queue.insert(SeqNum::new(120), b"", true);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.sack_blocks(4), []);
+        assert_eq!(queue.pop(SeqNum::new(120)), (Vec::new(), true));
+        assert!(queue.is_empty());
+    }
+
+    /// The segments of a stream arriving shuffled and duplicated are put back in order, also
+    /// across the 2^32 boundary of the sequence number.
+    #[test]
+    fn reassemble_shuffled_segments() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let original: Vec<u8> = (0..rng.gen_range(1..2000)).map(|_| rng.gen()).collect();
+            let initial = SeqNum::new(u32::MAX - rng.gen_range(0..3000));
+
+            let mut segments = Vec::new();
+            let mut offset = 0;
+            while offset < original.len() {
+                let end = (offset + rng.gen_range(1..100)).min(original.len());
+                segments.push((offset, end));
+                // Some segments are retransmitted with other boundaries.
+                if rng.gen_bool(0.3) {
+                    let start = rng.gen_range(0..end);
+                    segments.push((start, (start + rng.gen_range(1..200)).min(original.len())));
+                }
+                offset = end;
+            }
+            segments.shuffle(&mut rng);
+
+            let mut queue = ReassemblyQueue::new();
+            let mut stream = Vec::new();
+            let mut next = initial;
+            for (start, end) in segments {
+                let sequence_number = initial + start as u32;
+                receive(
+                    &mut queue,
+                    &mut stream,
+                    &mut next,
+                    sequence_number,
+                    &original[start..end],
+                );
+            }
+
+            assert_eq!(stream, original);
+            assert_eq!(next, initial + original.len() as u32);
+            assert!(queue.is_empty());
+        }
+    }
+
+    /// A retransmission queue of segments of the sizes, the ones with true SACKed.
+    fn retransmission_queue(segments: &[(usize, bool)]) -> VecDeque<RetransmissionQueueEntry> {
+        let mut sequence_number = SeqNum::new(u32::MAX - 1000);
+        segments
+            .iter()
+            .map(|&(size, sacked)| {
+                let mut entry =
+                    RetransmissionQueueEntry::new(sequence_number, TcpFlags::ACK, &vec![0; size]);
+                entry.sacked = sacked;
+                sequence_number += size as u32;
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nothing_lost_without_sack() {
+        let queue = retransmission_queue(&[(1000, false); 5]);
+        assert_eq!(lost_segments(&queue, 1000), [false; 5]);
+        assert_eq!(lost_segments(&VecDeque::new(), 1000), []);
+    }
+
+    #[test]
+    fn lost_with_dup_thresh_segments_sacked_above() {
+        let queue = retransmission_queue(&[
+            (100, false),
+            (100, false),
+            (100, true),
+            (100, false),
+            (100, true),
+            (100, true),
+            (100, false),
+        ]);
+        assert_eq!(
+            lost_segments(&queue, 1000),
+            [true, true, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn lost_with_more_than_dup_thresh_minus_1_mss_sacked_above() {
+        // Two segments of 1000 bytes SACKed are not more than 2 * SMSS.
+        let queue = retransmission_queue(&[(1000, false), (1000, true), (1000, true)]);
+        assert_eq!(lost_segments(&queue, 1000), [false; 3]);
+
+        // One more byte is.
+        let queue = retransmission_queue(&[(1000, false), (1000, true), (1001, true)]);
+        assert_eq!(lost_segments(&queue, 1000), [true, false, false]);
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    cmp::Ordering,
+    ops::{Add, AddAssign, Sub},
+};
+
+/// Sequence number.
+/// The sequence number space wraps around at 2^32, so comparison and arithmetic are done in serial
+/// number arithmetic. ref. RFC 1982 with SERIAL_BITS = 32.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SeqNum(u32);
+
+impl SeqNum {
+    pub const fn new(sequence_number: u32) -> Self {
+        Self(sequence_number)
+    }
+
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(sequence_number: u32) -> Self {
+        Self(sequence_number)
+    }
+}
+
+impl From<SeqNum> for u32 {
+    fn from(sequence_number: SeqNum) -> Self {
+        sequence_number.0
+    }
+}
+
+impl std::fmt::Display for SeqNum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// s + n. Addition modulo 2^32.
+impl Add<u32> for SeqNum {
+    type Output = Self;
+
+    fn add(self, n: u32) -> Self {
+        Self(self.0.wrapping_add(n))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, n: u32) {
+        *self = *self + n;
+    }
+}
+
+/// s - n. Subtraction modulo 2^32.
+impl Sub<u32> for SeqNum {
+    type Output = Self;
+
+    fn sub(self, n: u32) -> Self {
+        Self(self.0.wrapping_sub(n))
+    }
+}
+
+/// s1 - s2. The number of octets from s2 to s1 going forward in the sequence space.
+impl Sub<SeqNum> for SeqNum {
+    type Output = u32;
+
+    fn sub(self, other: Self) -> u32 {
+        self.0.wrapping_sub(other.0)
+    }
+}
+
+/// s1 < s2 if s2 is less than 2^31 ahead of s1. The comparison is undefined, and therefore all
+/// of <, <=, > and >= are false, when s1 and s2 are exactly 2^31 apart.
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match other.0.wrapping_sub(self.0) {
+            0 => Some(Ordering::Equal),
+            distance if distance < 1 << 31 => Some(Ordering::Less),
+            distance if distance > 1 << 31 => Some(Ordering::Greater),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+// Negated comparisons are what is being tested for the undefined case.
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    const ITERATIONS: usize = 100_000;
+
+    /// Random (s, n) pairs with 0 < n < 2^31, including ones where s + n crosses 2^32.
+    fn random_pairs() -> impl Iterator<Item = (SeqNum, u32)> {
+        let mut rng = rand::thread_rng();
+        (0..ITERATIONS).map(move |i| {
+            let n = rng.gen_range(1..1 << 31);
+            let s = if i % 2 == 0 {
+                rng.gen()
+            } else {
+                // Just before the wrap around.
+                u32::MAX - rng.gen_range(0..n)
+            };
+            (SeqNum::new(s), n)
+        })
+    }
+
+    #[test]
+    fn add_and_sub_are_inverse() {
+        for (s, n) in random_pairs() {
+            assert_eq!(s + n - n, s, "s = {}, n = {}", s, n);
+            assert_eq!((s + n) - s, n, "s = {}, n = {}", s, n);
+            assert_eq!(s - (s - n), n, "s = {}, n = {}", s, n);
+
+            let mut t = s;
+            t += n;
+            assert_eq!(t, s + n, "s = {}, n = {}", s, n);
+        }
+    }
+
+    #[test]
+    fn ordering_agrees_with_add() {
+        for (s, n) in random_pairs() {
+            let t = s + n;
+            assert!(s < t, "s = {}, n = {}", s, n);
+            assert!(s <= t, "s = {}, n = {}", s, n);
+            assert!(t > s, "s = {}, n = {}", s, n);
+            assert!(t >= s, "s = {}, n = {}", s, n);
+            assert!(!(t < s), "s = {}, n = {}", s, n);
+            assert!(!(s >= t), "s = {}, n = {}", s, n);
+            assert_eq!(s.partial_cmp(&t), Some(Ordering::Less));
+            assert_eq!(t.partial_cmp(&s), Some(Ordering::Greater));
+
+            assert!(s - n < s, "s = {}, n = {}", s, n);
+        }
+    }
+
+    #[test]
+    fn ordering_across_wrap_around() {
+        assert!(SeqNum::new(u32::MAX) < SeqNum::new(0));
+        assert!(SeqNum::new(u32::MAX - 10) < SeqNum::new(10));
+        assert!(SeqNum::new(10) > SeqNum::new(u32::MAX - 10));
+        assert_eq!(SeqNum::new(u32::MAX) + 1, SeqNum::new(0));
+        assert_eq!(SeqNum::new(0) - 1, SeqNum::new(u32::MAX));
+        assert_eq!(SeqNum::new(10) - SeqNum::new(u32::MAX - 10), 21);
+    }
+
+    #[test]
+    fn equal() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..ITERATIONS {
+            let s = SeqNum::new(rng.gen());
+            let t = SeqNum::new(s.get());
+            assert_eq!(s.partial_cmp(&t), Some(Ordering::Equal));
+            assert!(s <= t, "s = {}", s);
+            assert!(s >= t, "s = {}", s);
+            assert_eq!(s + 0, t);
+            assert_eq!(s - t, 0);
+        }
+    }
+
+    #[test]
+    fn undefined_when_2_31_apart() {
+        let mut rng = rand::thread_rng();
+        let values = [0, 1, 1 << 31, u32::MAX]
+            .into_iter()
+            .chain((0..ITERATIONS).map(|_| rng.gen()));
+        for s in values.map(SeqNum::new) {
+            let t = s + (1 << 31);
+            for (a, b) in [(s, t), (t, s)] {
+                assert_eq!(a.partial_cmp(&b), None, "a = {}, b = {}", a, b);
+                assert!(!(a < b), "a = {}, b = {}", a, b);
+                assert!(!(a <= b), "a = {}, b = {}", a, b);
+                assert!(!(a > b), "a = {}, b = {}", a, b);
+                assert!(!(a >= b), "a = {}, b = {}", a, b);
+                assert_ne!(a, b);
+            }
+        }
+    }
+}
@@ -1,18 +1,57 @@
 use std::{
     collections::VecDeque,
-    net::{IpAddr, Ipv4Addr},
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use log::debug;
+use log::{debug, warn};
+use rand::Rng;
+
 use pnet::{
+    datalink,
     packet::{ip::IpNextHeaderProtocols, Packet},
     transport::{self, TransportChannelType, TransportProtocol, TransportSender},
 };
 
-use crate::packet::TcpPacket;
+use crate::{
+    error::Result,
+    packet::{TcpFlags, TcpOption, TcpPacket},
+    retransmission::{RetransmissionQueueEntry, RetransmissionTimer, MAX_RTO},
+    sack::{lost_segments, LossRecovery, ReassemblyQueue, DUP_THRESH},
+    seq::SeqNum,
+    tcp::{EventSubscribers, TcpEvent},
+    wait_queue::WaitQueue,
+};
+
+/// The default size of the receive buffer, set by TcpStream::set_receive_buffer_size().
+pub const TCP_DEFAULT_RECEIVE_BUFFER_SIZE: usize = 1 << 20;
+
+/// The largest window expressible with window scaling, 2^30. ref. RFC 7323 2.3
+const TCP_MAX_WINDOW: usize = 1 << 30;
+
+/// The largest shift count of the Window Scale option. ref. RFC 7323 2.3
+const TCP_MAX_WINDOW_SHIFT: u8 = 14;
+
+/// The size of the IP and TCP headers without options, which the MSS excludes from the MTU.
+const TCP_IP_HEADER_SIZE: usize = 40;
+
+/// The MTU assumed when that of the interface is unknown, the one of Ethernet.
+const DEFAULT_MTU: usize = 1500;
+
+/// The MSS assumed when the remote host sends no MSS option. ref. RFC 9293 3.7.1
+const TCP_DEFAULT_MSS: usize = 536;
+
+/// The space taken by the Timestamps option and the two NOPs aligning it, which reduces the data
+/// sent in a segment. ref. RFC 6691
+const TCP_TIMESTAMPS_OPTION_SIZE: usize = 12;
+
+/// How long TS.Recent stays valid while the connection is idle. ref. RFC 7323 5.5
+const PAWS_IDLE_TIMEOUT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
 
-const TCP_SOCKET_BUFFER_SIZE: usize = 4380;
+/// The origin of the timestamp clock, which ticks every millisecond.
+static TIMESTAMP_EPOCH: OnceLock<Instant> = OnceLock::new();
 
 /// Four-tuple uniquely identifying a TCP socket.
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
@@ -23,6 +62,16 @@ pub struct TcpSocketId {
     pub remote_port: u16,
 }
 
+impl TcpSocketId {
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.local_address, self.local_port)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.remote_address, self.remote_port)
+    }
+}
+
 impl std::fmt::Display for TcpSocketId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -37,31 +86,34 @@ impl std::fmt::Display for TcpSocketId {
 #[derive(Debug)]
 pub struct SendSequenceVariables {
     /// Send unacknowledged.
-    pub una: u32,
+    pub una: SeqNum,
     /// Send next.
-    pub nxt: u32,
+    pub nxt: SeqNum,
     /// Send window.
-    pub wnd: u16,
+    pub wnd: u32,
+    /// Snd.Wind.Shift, the shift count applied to the window field of the segments received.
+    pub wnd_shift: u8,
     /// Send urgent pointer.
     pub up: u16,
     /// Segment sequence number used for last window update.
-    pub wl1: u32,
+    pub wl1: SeqNum,
     /// Segment acknowledgment number used for last window update.
-    pub wl2: u32,
+    pub wl2: SeqNum,
     /// Initial send sequence number.
-    pub iss: u32,
+    pub iss: SeqNum,
 }
 
 impl SendSequenceVariables {
-    pub fn with_window_size(wnd: u16) -> Self {
+    pub fn with_window_size(wnd: u32) -> Self {
         Self {
-            una: 0,
-            nxt: 0,
+            una: SeqNum::new(0),
+            nxt: SeqNum::new(0),
             wnd,
+            wnd_shift: 0,
             up: 0,
-            wl1: 0,
-            wl2: 0,
-            iss: 0,
+            wl1: SeqNum::new(0),
+            wl2: SeqNum::new(0),
+            iss: SeqNum::new(0),
         }
     }
 }
@@ -70,22 +122,25 @@ impl SendSequenceVariables {
 #[derive(Debug)]
 pub struct ReceiveSequenceVariables {
     /// Receive next.
-    pub nxt: u32,
+    pub nxt: SeqNum,
     /// Receive window.
-    pub wnd: u16,
+    pub wnd: u32,
+    /// Rcv.Wind.Shift, the shift count applied to the window field of the segments sent.
+    pub wnd_shift: u8,
     /// Receive urgent pointer.
     pub up: u16,
     /// Initial receive sequence number.
-    pub irs: u32,
+    pub irs: SeqNum,
 }
 
 impl ReceiveSequenceVariables {
-    pub fn with_window_size(wnd: u16) -> Self {
+    pub fn with_window_size(wnd: u32) -> Self {
         Self {
-            nxt: 0,
+            nxt: SeqNum::new(0),
             wnd,
+            wnd_shift: 0,
             up: 0,
-            irs: 0,
+            irs: SeqNum::new(0),
         }
     }
 }
@@ -119,6 +174,61 @@ pub struct TcpSocket {
     pub connected_socket_queue: VecDeque<TcpSocketId>,
     /// A listening socket. Only used by the connected socket.
     pub listening_socket: Option<TcpSocketId>,
+    /// Received data that has not been read by the user yet.
+    pub receive_buffer: VecDeque<u8>,
+    /// Data received out of order, waiting for the gap before it to be filled.
+    pub reassembly_queue: ReassemblyQueue,
+    /// The maximum amount of data in the receive buffer.
+    pub receive_buffer_size: usize,
+    /// Whether the read half of the connection has been shut down.
+    pub read_shutdown: bool,
+    /// Whether accept(), recv() and send() return WouldBlock instead of waiting.
+    pub nonblocking: bool,
+    /// How long recv() waits for data. None means forever.
+    pub read_timeout: Option<Duration>,
+    /// How long send() waits for the send window to open. None means forever.
+    pub write_timeout: Option<Duration>,
+    /// Whether the last send() has left data that does not fit in the send window.
+    pub send_pending: bool,
+    /// The MSS advertised to the remote host, derived from the MTU of the interface.
+    pub receive_mss: u16,
+    /// The largest amount of data sent in a segment, the smaller of the MSS of the remote host and
+    /// our own.
+    pub send_mss: usize,
+    /// Whether both hosts have sent the Window Scale option. ref. RFC 7323 2.2
+    pub window_scaling: bool,
+    /// Whether both hosts have sent the Timestamps option. ref. RFC 7323 3.2
+    pub timestamps: bool,
+    /// A random offset added to the timestamp clock so that the timestamps do not reveal the
+    /// uptime. ref. RFC 7323 5.4
+    timestamp_offset: u32,
+    /// TS.Recent, the timestamp to be echoed in TSecr.
+    pub ts_recent: u32,
+    /// When TS.Recent was last updated.
+    pub ts_recent_updated_at: Instant,
+    /// Last.ACK.sent, the acknowledgment number in the last segment sent.
+    pub last_ack_sent: SeqNum,
+    /// Whether both hosts have sent the SACK-Permitted option. ref. RFC 2018 2
+    pub sack_permitted: bool,
+    /// The loss recovery driven by the SACK blocks received.
+    pub recovery: LossRecovery,
+    /// Segments sent but not acknowledged yet, in order of sequence number.
+    pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
+    pub retransmission_timer: RetransmissionTimer,
+    /// When the 2MSL timeout of TIME-WAIT expires. Only used in TIME-WAIT.
+    pub time_wait_expires_at: Option<Instant>,
+    /// When the FIN-WAIT-2 timeout expires. Only used in FIN-WAIT-2 after both halves have been
+    /// shut down.
+    pub fin_wait_2_expires_at: Option<Instant>,
+    /// When the persist timer expires and the next window probe is sent. Only used while the
+    /// remote host advertises a zero window.
+    persist_expires_at: Option<Instant>,
+    /// How many window probes have been sent since the window was closed.
+    persist_probes: u32,
+    /// Users waiting for something to happen on the socket.
+    pub wait_queue: Arc<WaitQueue>,
+    /// Users subscribing to the events of the stack, to which state changes are published.
+    event_subscribers: Arc<EventSubscribers>,
     /// A transmission channel.
     /// This channel uses a raw socket. When a TCP packet is written to this channel, it is transmitted
     /// with an IP header.
@@ -131,43 +241,653 @@ impl TcpSocket {
         local_port: u16,
         remote_address: Ipv4Addr,
         remote_port: u16,
+        event_subscribers: Arc<EventSubscribers>,
     ) -> Result<Self> {
         let (sender, _) = transport::transport_channel(
             1 << 16,
             TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
         )?;
 
+        let receive_mss =
+            (interface_mtu(local_address).unwrap_or(DEFAULT_MTU) - TCP_IP_HEADER_SIZE) as u16;
+
         Ok(Self {
             local_address,
             local_port,
             remote_address,
             remote_port,
             // FIXME: Since the snd.wnd is determined by the remtoe host, it is not appropriate that the initial value is our buffer size. maybe...
-            snd: SendSequenceVariables::with_window_size(TCP_SOCKET_BUFFER_SIZE as u16),
-            rcv: ReceiveSequenceVariables::with_window_size(TCP_SOCKET_BUFFER_SIZE as u16),
+            snd: SendSequenceVariables::with_window_size(TCP_DEFAULT_RECEIVE_BUFFER_SIZE as u32),
+            rcv: ReceiveSequenceVariables::with_window_size(TCP_DEFAULT_RECEIVE_BUFFER_SIZE as u32),
             state: TcpState::Closed,
             connected_socket_queue: VecDeque::new(),
             listening_socket: None,
+            // The buffer grows as data arrives, so that large buffers cost nothing while empty.
+            receive_buffer: VecDeque::new(),
+            reassembly_queue: ReassemblyQueue::new(),
+            receive_buffer_size: TCP_DEFAULT_RECEIVE_BUFFER_SIZE,
+            read_shutdown: false,
+            nonblocking: false,
+            read_timeout: None,
+            write_timeout: None,
+            send_pending: false,
+            receive_mss,
+            send_mss: TCP_DEFAULT_MSS.min(receive_mss as usize),
+            window_scaling: false,
+            timestamps: false,
+            timestamp_offset: rand::thread_rng().gen(),
+            ts_recent: 0,
+            ts_recent_updated_at: Instant::now(),
+            last_ack_sent: SeqNum::new(0),
+            sack_permitted: false,
+            recovery: LossRecovery::new(),
+            retransmission_queue: VecDeque::new(),
+            retransmission_timer: RetransmissionTimer::new(),
+            time_wait_expires_at: None,
+            fin_wait_2_expires_at: None,
+            persist_expires_at: None,
+            persist_probes: 0,
+            wait_queue: Arc::new(WaitQueue::new()),
+            event_subscribers,
             sender,
         })
     }
 
     /// Send a TCP packet.
+    /// A segment that occupies sequence space is kept in the retransmission queue until it is
+    /// acknowledged.
     pub fn send_tcp_packet(
         &mut self,
-        sequence_number: u32,
-        acknowledgment_number: u32,
+        sequence_number: SeqNum,
+        acknowledgment_number: SeqNum,
         flags: u8,
         payload: &[u8],
     ) -> Result<usize> {
-        let mut packet = TcpPacket::new(payload.len());
+        let sent_size = self.transmit(sequence_number, acknowledgment_number, flags, payload)?;
+
+        let entry = RetransmissionQueueEntry::new(sequence_number, flags, payload);
+        if entry.segment_length() > 0 {
+            self.retransmission_queue.push_back(entry);
+            // RFC 6298 (5.1).
+            if !self.retransmission_timer.is_running() {
+                self.retransmission_timer.start();
+            }
+        }
+
+        Ok(sent_size)
+    }
+
+    /// Record the options of the SYN or SYN|ACK received from the remote host.
+    pub fn process_syn_options(&mut self, packet: &TcpPacket) {
+        let mut window_shift = None;
+        let mut timestamp = None;
+        let mut sack_permitted = false;
+        for option in packet.options() {
+            match option {
+                TcpOption::Mss(mss) if mss > 0 => {
+                    debug!("{} : MSS of the remote host is {}.", self.id(), mss);
+                    // Segments larger than our own MSS would be fragmented on our interface.
+                    self.send_mss = (mss as usize).min(self.receive_mss as usize);
+                }
+                TcpOption::WindowScale(shift_count) => window_shift = Some(shift_count),
+                TcpOption::Timestamps(value, _) => timestamp = Some(value),
+                TcpOption::SackPermitted => sack_permitted = true,
+                _ => {}
+            }
+        }
+
+        self.sack_permitted = sack_permitted;
+        if sack_permitted {
+            debug!("{} : SACK permitted.", self.id());
+        }
+
+        // Without the Timestamps option from the remote host, no timestamps are sent.
+        self.timestamps = timestamp.is_some();
+        if let Some(value) = timestamp {
+            debug!("{} : Timestamps enabled.", self.id());
+            self.ts_recent = value;
+            self.ts_recent_updated_at = Instant::now();
+            self.send_mss = self
+                .send_mss
+                .saturating_sub(TCP_TIMESTAMPS_OPTION_SIZE)
+                .max(1);
+        }
+
+        match window_shift {
+            Some(shift_count) => {
+                debug!(
+                    "{} : Window scale of the remote host is {}.",
+                    self.id(),
+                    shift_count
+                );
+                if shift_count > TCP_MAX_WINDOW_SHIFT {
+                    warn!(
+                        "{} : Window scale {} is too large, using {}.",
+                        self.id(),
+                        shift_count,
+                        TCP_MAX_WINDOW_SHIFT
+                    );
+                }
+                self.snd.wnd_shift = shift_count.min(TCP_MAX_WINDOW_SHIFT);
+                self.window_scaling = true;
+            }
+            // The remote host does not scale the window, so neither do we.
+            None => {
+                self.snd.wnd_shift = 0;
+                self.rcv.wnd_shift = 0;
+                self.window_scaling = false;
+            }
+        }
+    }
+
+    /// Reject an old duplicate segment by PAWS, or update TS.Recent with its timestamp.
+    /// Returns false if the segment must be dropped, in which case an ACK is sent. Not for a RST,
+    /// which is never rejected by PAWS. ref. RFC 7323 5.3
+    pub fn process_timestamps(&mut self, packet: &TcpPacket) -> Result<bool> {
+        if !self.timestamps {
+            return Ok(true);
+        }
+        // A segment without timestamps is accepted, as most implementations do.
+        let Some((value, _)) = packet.timestamps() else {return Ok(true);};
+
+        // SEG.TSval < TS.Recent. TS.Recent is no longer valid after a long idle time.
+        if (value.wrapping_sub(self.ts_recent) as i32) < 0
+            && self.ts_recent_updated_at.elapsed() < PAWS_IDLE_TIMEOUT
+        {
+            debug!(
+                "{} : Old duplicate segment rejected by PAWS, ACK sent.",
+                self.id()
+            );
+            self.send_tcp_packet(self.snd.nxt, self.rcv.nxt, TcpFlags::ACK, &[])?;
+            return Ok(false);
+        }
+
+        // SEG.SEQ =< Last.ACK.sent. Only the timestamp of the segment we are about to acknowledge
+        // is echoed, so that the RTT measured by the remote host includes delayed ACKs.
+        if packet.sequence_number() <= self.last_ack_sent {
+            self.ts_recent = value;
+            self.ts_recent_updated_at = Instant::now();
+        }
+
+        Ok(true)
+    }
+
+    /// The current value of the timestamp clock.
+    fn timestamp_now(&self) -> u32 {
+        let epoch = TIMESTAMP_EPOCH.get_or_init(Instant::now);
+        (epoch.elapsed().as_millis() as u32).wrapping_add(self.timestamp_offset)
+    }
+
+    /// Set the size of the receive buffer, up to 1 GiB. Must be called before sending the SYN,
+    /// since the window scale is chosen from it.
+    pub fn set_receive_buffer_size(&mut self, size: usize) {
+        self.receive_buffer_size = size.min(TCP_MAX_WINDOW);
+        // The smallest shift count with which the whole buffer can be advertised.
+        self.rcv.wnd_shift = 0;
+        while self.receive_buffer_size >> self.rcv.wnd_shift > u16::MAX as usize
+            && self.rcv.wnd_shift < TCP_MAX_WINDOW_SHIFT
+        {
+            self.rcv.wnd_shift += 1;
+        }
+        self.update_receive_window();
+    }
+
+    /// Send a FIN to close our side of the connection.
+    /// The state changes to FIN-WAIT-1, or LAST-ACK if the remote host has already sent a FIN.
+    pub fn send_fin(&mut self) -> Result<()> {
+        debug!("{} : FIN sent.", self.id());
+        self.send_tcp_packet(
+            self.snd.nxt,
+            self.rcv.nxt,
+            TcpFlags::FIN | TcpFlags::ACK,
+            &[],
+        )?;
+        self.snd.nxt += 1;
+
+        if self.state == TcpState::CloseWait {
+            self.set_state(TcpState::LastAck);
+        } else {
+            self.set_state(TcpState::FinWait1);
+        }
+
+        Ok(())
+    }
+
+    /// Send a RST to abort the connection. Nothing is sent unless the connection is synchronized.
+    pub fn send_reset(&mut self) -> Result<()> {
+        if matches!(
+            self.state,
+            TcpState::SynReceived
+                | TcpState::Established
+                | TcpState::FinWait1
+                | TcpState::FinWait2
+                | TcpState::CloseWait
+        ) {
+            // <SEQ=SND.NXT><CTL=RST>
+            debug!("{} : RST sent.", self.id());
+            self.send_tcp_packet(self.snd.nxt, SeqNum::new(0), TcpFlags::RST, &[])?;
+        }
+
+        Ok(())
+    }
+
+    /// Retransmit the earliest unacknowledged segment and back off the timer.
+    /// Called when the retransmission timer expires. RFC 6298 (5.4) - (5.6).
+    pub fn retransmit(&mut self) -> Result<()> {
+        let Some(entry) = self.retransmission_queue.front() else {
+            self.retransmission_timer.stop();
+            return Ok(());
+        };
+
+        debug!(
+            "{} : Retransmission timeout ({:?}), retransmitting the segment {}.",
+            self.id(),
+            self.retransmission_timer.rto(),
+            entry.sequence_number
+        );
+        let end = entry.end();
+        // The timer is backed off even if the transmission fails, so that it is retried later.
+        self.retransmission_timer.back_off();
+        self.retransmission_timer.start();
+
+        // The loss recovery is terminated, and no new one starts until everything sent so far is
+        // acknowledged. Meanwhile, the segments not SACKed are retransmitted as the ACKs arrive.
+        // ref. RFC 6675 5.1
+        if self.sack_permitted {
+            if self.recovery.in_recovery {
+                debug!(
+                    "{} : Loss recovery terminated by the retransmission timeout.",
+                    self.id()
+                );
+            }
+            self.recovery.in_recovery = false;
+            self.recovery.recovery_point = Some(self.snd.nxt);
+            self.recovery.high_rxt = end;
+        }
+
+        self.retransmit_segment(0)
+    }
+
+    /// Send a window probe when the persist timer expires, and start or stop the timer.
+    /// The timer runs while the remote host advertises a zero window, nothing is in flight and
+    /// the user has data to send, since the ACK opening the window may be lost. The interval is
+    /// doubled after each probe. ref. RFC 1122 4.2.2.17
+    pub fn process_persist_timer(&mut self, now: Instant) -> Result<()> {
+        if !(matches!(self.state, TcpState::Established | TcpState::CloseWait)
+            && self.send_pending
+            && self.snd.wnd == 0
+            && self.retransmission_queue.is_empty())
+        {
+            self.persist_expires_at = None;
+            self.persist_probes = 0;
+            return Ok(());
+        }
+
+        match self.persist_expires_at {
+            Some(expires_at) if now < expires_at => return Ok(()),
+            Some(_) => {
+                // A segment below SND.UNA is not acceptable, so the remote host answers it with
+                // an ACK carrying the current window.
+                debug!("{} : Persist timer expired, window probe sent.", self.id());
+                self.persist_probes += 1;
+                self.transmit(self.snd.una - 1, self.rcv.nxt, TcpFlags::ACK, &[])?;
+            }
+            None => debug!("{} : Persist timer started.", self.id()),
+        }
+        let timeout = self
+            .retransmission_timer
+            .rto()
+            .saturating_mul(1 << self.persist_probes.min(16))
+            .min(MAX_RTO);
+        self.persist_expires_at = Some(now + timeout);
+
+        Ok(())
+    }
+
+    /// Retransmit the segment at the index of the retransmission queue.
+    fn retransmit_segment(&mut self, index: usize) -> Result<()> {
+        let entry = &mut self.retransmission_queue[index];
+        entry.transmission_count += 1;
+        entry.transmitted_at = Instant::now();
+        let sequence_number = entry.sequence_number;
+        let flags = entry.flags;
+        let payload = entry.payload.clone();
+
+        // The first SYN has no acknowledgment number.
+        let acknowledgment_number = if flags & TcpFlags::ACK != 0 {
+            self.rcv.nxt
+        } else {
+            SeqNum::new(0)
+        };
+        self.transmit(sequence_number, acknowledgment_number, flags, &payload)?;
+
+        Ok(())
+    }
+
+    /// Update the scoreboard with the SACK blocks of an ACK, and start or continue the loss
+    /// recovery. acknowledged tells whether the ACK has advanced SND.UNA. ref. RFC 6675 5
+    pub fn process_sack(&mut self, packet: &TcpPacket, acknowledged: bool) -> Result<()> {
+        if !self.sack_permitted {
+            return Ok(());
+        }
+        let newly_sacked = self.update_scoreboard(packet);
+
+        if self.recovery.in_recovery {
+            // An ACK for RecoveryPoint ends the loss recovery.
+            if self
+                .recovery
+                .recovery_point
+                .is_some_and(|recovery_point| recovery_point <= self.snd.una)
+            {
+                debug!("{} : Loss recovery completed.", self.id());
+                self.recovery.in_recovery = false;
+                self.recovery.dup_acks = 0;
+                return Ok(());
+            }
+            // (C) Retransmit more as the ACK has taken segments out of the network.
+            return self.send_recovery_segments();
+        }
+
+        // An ACK that does not advance SND.UNA but reports new SACK information is a duplicate
+        // ACK. ref. RFC 6675 2
+        if acknowledged {
+            self.recovery.dup_acks = 0;
+        } else if newly_sacked {
+            self.recovery.dup_acks += 1;
+        }
+
+        // No new loss recovery starts until the data sent before the last one, or before the last
+        // retransmission timeout, is acknowledged.
+        if let Some(recovery_point) = self
+            .recovery
+            .recovery_point
+            .filter(|&recovery_point| self.snd.una < recovery_point)
+        {
+            if acknowledged {
+                self.retransmit_after_timeout(recovery_point)?;
+            }
+            return Ok(());
+        }
+        // (1) - (3) The loss recovery starts on the DupThresh-th duplicate ACK, or when the first
+        // unacknowledged segment is deemed lost.
+        let lost = lost_segments(&self.retransmission_queue, self.send_mss);
+        if self.recovery.dup_acks >= DUP_THRESH || lost.first().is_some_and(|&lost| lost) {
+            self.enter_loss_recovery()?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the segments covered by the SACK blocks of the ACK. Returns true if any segment has
+    /// been newly SACKed. ref. RFC 6675 5 Update()
+    fn update_scoreboard(&mut self, packet: &TcpPacket) -> bool {
+        let mut newly_sacked = false;
+        for (left, right) in packet.sack_blocks() {
+            // A block at or below SND.UNA, or beyond what has been sent, is ignored.
+            if !(left < right && self.snd.una < right && right <= self.snd.nxt) {
+                continue;
+            }
+            for entry in self.retransmission_queue.iter_mut() {
+                if !entry.sacked && left <= entry.sequence_number && entry.end() <= right {
+                    entry.sacked = true;
+                    newly_sacked = true;
+                }
+            }
+        }
+        newly_sacked
+    }
+
+    /// SetPipe(), the estimated amount of data in the network. ref. RFC 6675 4
+    fn pipe(&self, lost: &[bool]) -> usize {
+        self.retransmission_queue
+            .iter()
+            .zip(lost)
+            .filter(|(entry, _)| !entry.sacked)
+            .map(|(entry, &lost)| {
+                let length = entry.segment_length() as usize;
+                let mut pipe = 0;
+                // The original transmission is still in the network.
+                if !lost {
+                    pipe += length;
+                }
+                // The segment has been retransmitted by the loss recovery.
+                if entry.end() <= self.recovery.high_rxt {
+                    pipe += length;
+                }
+                pipe
+            })
+            .sum()
+    }
+
+    /// NextSeg(), the index of the segment to be retransmitted next by the loss recovery.
+    /// Sending new data, rule (2), is left to send(), as usable_window() allows it only while
+    /// cwnd - pipe >= 1 SMSS. ref. RFC 6675 4
+    fn next_segment(&mut self, lost: &[bool]) -> Option<usize> {
+        let highest_sacked = self
+            .retransmission_queue
+            .iter()
+            .rev()
+            .find(|entry| entry.sacked)
+            .map(RetransmissionQueueEntry::end);
+
+        if let Some(highest_sacked) = highest_sacked {
+            // The segments not SACKed, above HighRxt and below the highest SACKed octet.
+            let candidates = || {
+                self.retransmission_queue
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| {
+                        !entry.sacked
+                            && self.recovery.high_rxt <= entry.sequence_number
+                            && entry.end() <= highest_sacked
+                    })
+                    .map(|(i, _)| i)
+            };
+            // (1) The first one deemed lost, or (3) the first one.
+            if let Some(i) = candidates()
+                .find(|&i| lost[i])
+                .or_else(|| candidates().next())
+            {
+                self.recovery.high_rxt = self.retransmission_queue[i].end();
+                return Some(i);
+            }
+        }
+
+        // (4) The rescue retransmission of the last segment not SACKed, once per loss recovery,
+        // if more than one SMSS of data has not been SACKed.
+        let unsacked_bytes: usize = self
+            .retransmission_queue
+            .iter()
+            .filter(|entry| !entry.sacked)
+            .map(|entry| entry.segment_length() as usize)
+            .sum();
+        if self
+            .recovery
+            .rescue_rxt
+            .is_none_or(|rescue_rxt| rescue_rxt < self.snd.una)
+            && unsacked_bytes > self.send_mss
+        {
+            let i = self
+                .retransmission_queue
+                .iter()
+                .rposition(|entry| !entry.sacked)?;
+            // The segment has not been retransmitted by the rules above.
+            if self.recovery.high_rxt <= self.retransmission_queue[i].sequence_number {
+                self.recovery.rescue_rxt = self.recovery.recovery_point;
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    /// Start the loss recovery by retransmitting the first unacknowledged segment.
+    /// ref. RFC 6675 5 (4)
+    fn enter_loss_recovery(&mut self) -> Result<()> {
+        debug!(
+            "{} : Segment {} deemed lost, entering loss recovery.",
+            self.id(),
+            self.snd.una
+        );
+        // (4.1) RecoveryPoint = HighData.
+        self.recovery.in_recovery = true;
+        self.recovery.recovery_point = Some(self.snd.nxt);
+        self.recovery.rescue_rxt = None;
+        // (4.2) cwnd = ssthresh = max(FlightSize / 2, 2 * SMSS). ref. RFC 5681 (4)
+        let flight_size = (self.snd.nxt - self.snd.una) as usize;
+        self.recovery.cwnd = (flight_size / 2).max(2 * self.send_mss);
+
+        // (4.3) Retransmit the first unacknowledged segment.
+        self.recovery.high_rxt = self.retransmission_queue[0].end();
+        self.retransmit_segment(0)?;
+
+        // (4.4) and (4.5)
+        self.send_recovery_segments()
+    }
+
+    /// Retransmit the next two segments not SACKed below RecoveryPoint after a retransmission
+    /// timeout, as slow start would on each ACK, so that every hole does not wait for a timeout
+    /// of its own. ref. RFC 6675 5.1
+    fn retransmit_after_timeout(&mut self, recovery_point: SeqNum) -> Result<()> {
+        for _ in 0..2 {
+            let Some(i) = self.retransmission_queue.iter().position(|entry| {
+                !entry.sacked
+                    && self.recovery.high_rxt <= entry.sequence_number
+                    && entry.end() <= recovery_point
+            }) else {
+                break;
+            };
+            debug!(
+                "{} : Retransmitting the segment {} after the retransmission timeout.",
+                self.id(),
+                self.retransmission_queue[i].sequence_number
+            );
+            self.recovery.high_rxt = self.retransmission_queue[i].end();
+            self.retransmit_segment(i)?;
+        }
+        Ok(())
+    }
+
+    /// Retransmit the segments chosen by NextSeg() while cwnd - pipe >= 1 SMSS.
+    /// ref. RFC 6675 5 (C)
+    fn send_recovery_segments(&mut self) -> Result<()> {
+        let lost = lost_segments(&self.retransmission_queue, self.send_mss);
+        while self.pipe(&lost) + self.send_mss <= self.recovery.cwnd {
+            let Some(i) = self.next_segment(&lost) else {break;};
+            debug!(
+                "{} : Retransmitting the segment {} in loss recovery.",
+                self.id(),
+                self.retransmission_queue[i].sequence_number
+            );
+            self.retransmit_segment(i)?;
+        }
+        Ok(())
+    }
+
+    /// Advance SND.UNA to SEG.ACK and remove the acknowledged segments from the retransmission
+    /// queue.
+    pub fn acknowledge(&mut self, packet: &TcpPacket) {
+        let acknowledgment_number = packet.acknowledgment_number();
+        self.snd.una = acknowledgment_number;
+
+        let now = Instant::now();
+        let mut acknowledged = false;
+        let mut rtt = None;
+        while let Some(entry) = self.retransmission_queue.front() {
+            if acknowledgment_number < entry.end() {
+                break;
+            }
+            // Karn's algorithm: a retransmitted segment gives an ambiguous RTT sample.
+            if entry.transmission_count == 1 {
+                rtt = Some(now - entry.transmitted_at);
+            }
+            self.retransmission_queue.pop_front();
+            acknowledged = true;
+        }
+        // The echoed timestamp tells when the acknowledged segment was sent, even if it has been
+        // retransmitted. ref. RFC 7323 4.1
+        if acknowledged && self.timestamps {
+            if let Some((_, echo_reply)) = packet.timestamps() {
+                let elapsed = self.timestamp_now().wrapping_sub(echo_reply);
+                rtt = Some(Duration::from_millis(elapsed as u64));
+            }
+        }
+        if let Some(rtt) = rtt {
+            self.retransmission_timer.update(rtt);
+        }
+
+        // RFC 6298 (5.2) and (5.3).
+        if self.retransmission_queue.is_empty() {
+            self.retransmission_timer.stop();
+        } else if acknowledged {
+            self.retransmission_timer.start();
+        }
+    }
+
+    /// Build a TCP packet and write it to the transmission channel.
+    fn transmit(
+        &mut self,
+        sequence_number: SeqNum,
+        acknowledgment_number: SeqNum,
+        flags: u8,
+        payload: &[u8],
+    ) -> Result<usize> {
+        // The MSS and the window scale are only sent in a SYN. The window scale is sent in a
+        // SYN|ACK only if the remote host has sent it. ref. RFC 9293 3.7.1, RFC 7323 2.2
+        let mut options = Vec::new();
+        if flags & TcpFlags::SYN != 0 {
+            options.push(TcpOption::Mss(self.receive_mss));
+            if flags & TcpFlags::ACK == 0 || self.window_scaling {
+                options.push(TcpOption::Nop);
+                options.push(TcpOption::WindowScale(self.rcv.wnd_shift));
+            }
+        }
+        // The SYN offers the timestamps. Once negotiated, they are sent in every segment but a
+        // RST. ref. RFC 7323 3.2
+        let syn_only = flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN;
+        if (syn_only || self.timestamps) && flags & TcpFlags::RST == 0 {
+            options.push(TcpOption::Nop);
+            options.push(TcpOption::Nop);
+            options.push(TcpOption::Timestamps(self.timestamp_now(), self.ts_recent));
+        }
+        // The SYN offers SACK, and the SYN|ACK accepts it only if the remote host has offered it.
+        // ref. RFC 2018 2
+        if flags & TcpFlags::SYN != 0 && (flags & TcpFlags::ACK == 0 || self.sack_permitted) {
+            options.push(TcpOption::Nop);
+            options.push(TcpOption::Nop);
+            options.push(TcpOption::SackPermitted);
+        }
+        // An ACK without data reports the data received out of order, the most recently received
+        // block first. Three blocks fit alongside the timestamps. Data segments carry no blocks so
+        // that they do not exceed the MSS. ref. RFC 2018 3, 4
+        if self.sack_permitted
+            && flags & (TcpFlags::SYN | TcpFlags::RST | TcpFlags::ACK) == TcpFlags::ACK
+            && payload.is_empty()
+        {
+            let blocks = self
+                .reassembly_queue
+                .sack_blocks(if self.timestamps { 3 } else { 4 });
+            if !blocks.is_empty() {
+                options.push(TcpOption::Nop);
+                options.push(TcpOption::Nop);
+                options.push(TcpOption::Sack(blocks));
+            }
+        }
+        if flags & TcpFlags::ACK != 0 {
+            self.last_ack_sent = acknowledgment_number;
+        }
+        let mut packet = TcpPacket::new(&options, payload.len());
         packet.set_source_port(self.local_port);
         packet.set_destination_port(self.remote_port);
         packet.set_sequence_number(sequence_number);
         packet.set_acknowledgment_number(acknowledgment_number);
-        packet.set_data_offset(5); // = TCP_HEADER_SIZE / 4
         packet.set_flags(flags);
-        packet.set_window_size(self.rcv.wnd);
+        // The window field of a SYN is never scaled. ref. RFC 7323 2.2
+        if flags & TcpFlags::SYN != 0 {
+            packet.set_window_size(self.rcv.wnd.min(u16::MAX as u32) as u16);
+        } else {
+            packet.set_window_size(self.advertised_window());
+        }
         packet.set_payload(payload);
         // TODO: Calculate the checksum without pnet's help.
         packet.set_checksum(pnet::util::ipv4_checksum(
@@ -182,11 +902,13 @@ impl TcpSocket {
         let sent_size = self
             .sender
             .send_to(&packet, IpAddr::V4(self.remote_address))
-            .context(format!(
-                "{} : Failed to send the packet {:X?}",
-                self.id(),
-                &packet.packet()
-            ))?;
+            .inspect_err(|_| {
+                warn!(
+                    "{} : Failed to send the packet {:X?}",
+                    self.id(),
+                    &packet.packet()
+                )
+            })?;
 
         debug!(
             "{} : Sent {} bytes {:X?}",
@@ -198,6 +920,66 @@ impl TcpSocket {
         Ok(sent_size)
     }
 
+    /// Change the state of the socket.
+    pub fn set_state(&mut self, state: TcpState) {
+        debug!(
+            "{} : State changed from {:?} to {:?}.",
+            self.id(),
+            self.state,
+            state
+        );
+        self.event_subscribers
+            .publish(TcpEvent::StateChanged(self.id(), self.state, state));
+        self.state = state;
+    }
+
+    /// Recalculate the receive window from the free space of the receive buffer.
+    pub fn update_receive_window(&mut self) {
+        self.rcv.wnd = (self.receive_buffer_size - self.receive_buffer.len()) as u32;
+    }
+
+    /// The window field of the segments sent, RCV.WND scaled down by Rcv.Wind.Shift.
+    pub fn advertised_window(&self) -> u16 {
+        (self.rcv.wnd >> self.rcv.wnd_shift).min(u16::MAX as u32) as u16
+    }
+
+    /// SEG.WND scaled up by Snd.Wind.Shift. The window field of a SYN is not scaled, so this must
+    /// not be used for it.
+    pub fn scaled_window(&self, packet: &TcpPacket) -> u32 {
+        (packet.window_size() as u32) << self.snd.wnd_shift
+    }
+
+    /// Get the amount of data that can be sent now, SND.UNA + SND.WND - SND.NXT.
+    /// This is 0 if the remote host has shrunk the window below what has already been sent.
+    /// During the loss recovery, it is also limited to cwnd - pipe, and new data is sent only if
+    /// at least 1 SMSS fits. ref. RFC 6675 4 NextSeg() (2)
+    pub fn usable_window(&self) -> usize {
+        let window_end = self.snd.una + self.snd.wnd;
+        if self.snd.nxt >= window_end {
+            return 0;
+        }
+        let usable_window = (window_end - self.snd.nxt) as usize;
+
+        if !self.recovery.in_recovery {
+            return usable_window;
+        }
+        let lost = lost_segments(&self.retransmission_queue, self.send_mss);
+        let congestion_window = self.recovery.cwnd.saturating_sub(self.pipe(&lost));
+        if congestion_window < self.send_mss {
+            return 0;
+        }
+        usable_window.min(congestion_window)
+    }
+
+    /// Check if the sequence number is in the receive window, that is,
+    /// RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND. Only RCV.NXT is acceptable for a zero window.
+    pub fn is_in_receive_window(&self, sequence_number: SeqNum) -> bool {
+        if self.rcv.wnd == 0 {
+            return sequence_number == self.rcv.nxt;
+        }
+        self.rcv.nxt <= sequence_number && sequence_number < self.rcv.nxt + self.rcv.wnd
+    }
+
     /// Get a four-tuple uniquely identifying this socket.
     pub fn id(&self) -> TcpSocketId {
         TcpSocketId {
@@ -208,3 +990,16 @@ impl TcpSocket {
         }
     }
 }
+
+/// Get the MTU of the interface to which the address is assigned.
+fn interface_mtu(address: Ipv4Addr) -> Option<usize> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.ips.iter().any(|ip| ip.ip() == address))?;
+    // pnet does not provide the MTU, so it is read from sysfs.
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", interface.name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
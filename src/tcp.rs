@@ -1,20 +1,36 @@
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Condvar, Mutex, RwLock},
+    io,
+    net::{IpAddr, Ipv4Addr, Shutdown},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    task::Waker,
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use log::{debug, info, warn};
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+
+use log::{debug, error, info, warn};
 use pnet::{
     packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket as PnetTcpPacket, Packet},
-    transport::{self, TransportChannelType},
+    transport::{self, TransportChannelType, TransportProtocol, TransportSender},
 };
 use rand::Rng;
 
 use crate::{
+    error::{Error, Result},
     packet::{TcpFlags, TcpPacket},
-    socket::{TcpSocket, TcpSocketId, TcpState},
+    poll::{Event, Events, Interest},
+    retransmission::CLOCK_GRANULARITY,
+    seq::SeqNum,
+    socket::{TcpSocket, TcpSocketId, TcpState, TCP_DEFAULT_RECEIVE_BUFFER_SIZE},
+    wait_queue::WaitQueue,
 };
 
 const TCP_PORT_RANGE_START: u16 = 49152;
@@ -22,9 +38,57 @@ const TCP_PORT_RANGE_END: u16 = 65535;
 
 const TCP_UNSPECIFIED_PORT: u16 = 0;
 
+/// Default number of SYN and SYN|ACK retransmissions, the same as Linux's tcp_syn_retries.
+const TCP_MAX_SYN_RETRANSMISSIONS: usize = 6;
+
+/// Maximum segment lifetime. A socket stays in TIME-WAIT for 2MSL.
+const TCP_MSL: Duration = Duration::from_secs(30);
+
+/// How long a connection whose both halves have been shut down waits in FIN-WAIT-2 for the FIN of
+/// the remote host, the same as Linux's tcp_fin_timeout.
+const TCP_FIN_WAIT_2_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long the receiving thread waits for a packet before checking if the stack has been shut
+/// down.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum TcpEvent {
+    /// The three-way handshake has been completed. Notified on the listening socket for a
+    /// connection opened passively.
     ConnectionEstablished(TcpSocketId),
+    /// Data has arrived and can be read.
+    DataReadable(TcpSocketId),
+    /// Data in flight has been acknowledged or the send window has been updated.
+    SendSpaceAvailable(TcpSocketId),
+    /// The remote host has sent a FIN, so no more data will arrive.
+    PeerClosed(TcpSocketId),
+    /// The state of the socket has changed from the former to the latter.
+    StateChanged(TcpSocketId, TcpState, TcpState),
+    /// The connection has been terminated and the socket has been deleted.
+    ConnectionClosed(TcpSocketId),
+    ConnectionReset(TcpSocketId),
+    ConnectionAborted(TcpSocketId),
+    ConnectionRefused(TcpSocketId),
+    ConnectionTimedOut(TcpSocketId),
+}
+
+impl TcpEvent {
+    /// The socket on which the event occurred.
+    pub fn socket_id(&self) -> TcpSocketId {
+        match *self {
+            Self::ConnectionEstablished(id)
+            | Self::DataReadable(id)
+            | Self::SendSpaceAvailable(id)
+            | Self::PeerClosed(id)
+            | Self::StateChanged(id, _, _)
+            | Self::ConnectionClosed(id)
+            | Self::ConnectionReset(id)
+            | Self::ConnectionAborted(id)
+            | Self::ConnectionRefused(id)
+            | Self::ConnectionTimedOut(id) => id,
+        }
+    }
 }
 
 impl std::fmt::Display for TcpEvent {
@@ -34,14 +98,87 @@ impl std::fmt::Display for TcpEvent {
             "{}",
             match self {
                 Self::ConnectionEstablished(id) => format!("{} : ConnectionEstablished", id),
+                Self::DataReadable(id) => format!("{} : DataReadable", id),
+                Self::SendSpaceAvailable(id) => format!("{} : SendSpaceAvailable", id),
+                Self::PeerClosed(id) => format!("{} : PeerClosed", id),
+                Self::StateChanged(id, from, to) => {
+                    format!("{} : StateChanged({:?} -> {:?})", id, from, to)
+                }
+                Self::ConnectionClosed(id) => format!("{} : ConnectionClosed", id),
+                Self::ConnectionReset(id) => format!("{} : ConnectionReset", id),
+                Self::ConnectionAborted(id) => format!("{} : ConnectionAborted", id),
+                Self::ConnectionRefused(id) => format!("{} : ConnectionRefused", id),
+                Self::ConnectionTimedOut(id) => format!("{} : ConnectionTimedOut", id),
             }
         )
     }
 }
 
+/// Users subscribing to the events of the stack.
+#[derive(Debug, Default)]
+pub struct EventSubscribers {
+    senders: Mutex<Vec<Sender<TcpEvent>>>,
+}
+
+impl EventSubscribers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a subscriber and return the receiving end of its channel.
+    pub fn subscribe(&self) -> Result<Receiver<TcpEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock()?.push(sender);
+        Ok(receiver)
+    }
+
+    /// Send the event to all the subscribers.
+    /// The subscribers whose receiver has been dropped are removed.
+    pub fn publish(&self, event: TcpEvent) {
+        // The senders are never left inconsistent, so a poisoned lock is still usable.
+        let mut senders = self
+            .senders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        senders.retain(|sender| sender.send(event).is_ok());
+    }
+}
+
+/// The result of checking the condition waited for.
+enum Readiness<T> {
+    Ready(T),
+    /// The condition is not met yet. Wait on the wait queue for a notification after the
+    /// generation.
+    Pending(Arc<WaitQueue>, u64),
+}
+
+/// Whether the stack is still running.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Health {
+    Running,
+    /// The receiving or timer thread has stopped for the reason.
+    Failed(String),
+    /// The stack has been shut down by shutdown_stack().
+    Stopped,
+}
+
 pub struct TcpStream {
     sockets: RwLock<HashMap<TcpSocketId, TcpSocket>>,
-    event_condvar: (Mutex<Option<TcpEvent>>, Condvar),
+    health: Mutex<Health>,
+    event_subscribers: Arc<EventSubscribers>,
+    /// How many times a SYN or SYN|ACK is retransmitted before giving up the connection.
+    max_syn_retransmissions: AtomicUsize,
+    /// The size of the receive buffer of new sockets.
+    receive_buffer_size: AtomicUsize,
+    /// Whether a RST is sent for a segment to a port not used by this stack.
+    reset_unowned_ports: AtomicBool,
+    /// A transmission channel for the RSTs sent in response to segments that belong to no
+    /// socket. Opened when the first one is sent.
+    reset_sender: Mutex<Option<TransportSender>>,
+    /// The receiving and timer threads, joined when the stack is shut down.
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Notified whenever something happens on any socket, for the users waiting in poll().
+    readiness_queue: WaitQueue,
 }
 
 impl TcpStream {
@@ -49,28 +186,247 @@ impl TcpStream {
         let sockets = RwLock::new(HashMap::new());
         let tcp = Arc::new(Self {
             sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
+            health: Mutex::new(Health::Running),
+            event_subscribers: Arc::new(EventSubscribers::new()),
+            max_syn_retransmissions: AtomicUsize::new(TCP_MAX_SYN_RETRANSMISSIONS),
+            receive_buffer_size: AtomicUsize::new(TCP_DEFAULT_RECEIVE_BUFFER_SIZE),
+            reset_unowned_ports: AtomicBool::new(false),
+            reset_sender: Mutex::new(None),
+            threads: Mutex::new(Vec::new()),
+            readiness_queue: WaitQueue::new(),
+        });
+
+        // The threads only hold a weak reference so that the stack is dropped when the user drops it.
+        let weak_tcp = Arc::downgrade(&tcp);
+        let receiving_thread = std::thread::spawn(move || {
+            Self::supervise(&weak_tcp, "receiving", Self::receive_handler);
         });
 
-        let cloned_tcp = Arc::clone(&tcp);
-        std::thread::spawn(move || {
-            // TODO: Handle error.
-            cloned_tcp.receive_handler().unwrap();
+        let weak_tcp = Arc::downgrade(&tcp);
+        let timer_thread = std::thread::spawn(move || {
+            Self::supervise(&weak_tcp, "timer", Self::timer_handler);
         });
 
+        tcp.threads
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .extend([receiving_thread, timer_thread]);
+
         tcp
     }
 
+    /// Report whether the receiving and timer threads are still running.
+    pub fn health(&self) -> Health {
+        // The health is never left inconsistent, so a poisoned lock is still usable.
+        self.health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Run a thread of the stack. If it stops with an error or a panic, the stack is marked as
+    /// failed and all the users waiting on the sockets are woken up to get the error.
+    fn supervise(tcp: &Weak<Self>, name: &str, handler: fn(&Weak<Self>) -> Result<()>) {
+        let reason = match panic::catch_unwind(AssertUnwindSafe(|| handler(tcp))) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => format!("The {} thread stopped: {}", name, e),
+            Err(_) => format!("The {} thread panicked", name),
+        };
+        error!("{}", reason);
+
+        // Nobody is waiting if the stack has already been dropped.
+        let Some(tcp) = tcp.upgrade() else {return;};
+        tcp.fail(reason);
+    }
+
+    /// Mark the stack as failed and wake up all the users waiting on the sockets.
+    fn fail(&self, reason: String) {
+        *self
+            .health
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Health::Failed(reason);
+
+        // The health is checked with the socket table locked in wait_until(), so the waiters
+        // either see the failure or are woken up here.
+        let socket_table = self
+            .sockets
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for socket in socket_table.values() {
+            let _ = socket.wait_queue.notify();
+        }
+        let _ = self.readiness_queue.notify();
+    }
+
+    /// Return an error if the stack has failed.
+    fn check_health(&self) -> Result<()> {
+        match self.health() {
+            Health::Running => Ok(()),
+            Health::Failed(reason) => Err(Error::StackFailed(reason)),
+            Health::Stopped => Err(Error::StackFailed("The stack has been shut down".into())),
+        }
+    }
+
+    /// Shut down the stack.
+    /// All the connections are aborted, sending a RST if synchronized, and the users waiting on
+    /// them get an error. The receiving and timer threads are stopped and joined, and the raw
+    /// sockets are released. The stack cannot be used any more. This is also done when the stack
+    /// is dropped.
+    pub fn shutdown_stack(&self) -> Result<()> {
+        {
+            let mut health = self.health.lock()?;
+            if *health == Health::Stopped {
+                return Ok(());
+            }
+            *health = Health::Stopped;
+        }
+        info!("Shutting down the stack.");
+
+        let mut socket_table = self.sockets.write()?;
+        let socket_ids: Vec<_> = socket_table.keys().copied().collect();
+        for socket_id in socket_ids {
+            if let Some(socket) = socket_table.get_mut(&socket_id) {
+                if let Err(e) = socket.send_reset() {
+                    warn!("{} : Failed to send a RST: {}", socket_id, e);
+                }
+            }
+            self.delete_socket(
+                &mut socket_table,
+                socket_id,
+                TcpEvent::ConnectionAborted(socket_id),
+            )?;
+        }
+        drop(socket_table);
+
+        let threads = std::mem::take(&mut *self.threads.lock()?);
+        for thread in threads {
+            // The stack may be dropped in one of its own threads, which stops by itself.
+            if thread.thread().id() == std::thread::current().id() {
+                continue;
+            }
+            // The threads never panic since the panics are caught in supervise().
+            let _ = thread.join();
+        }
+        debug!("Stopped the receiving and timer threads.");
+
+        // The receiving thread, which is the only one sending RSTs for unknown connections, has
+        // stopped, so the channel can be released.
+        *self.reset_sender.lock()? = None;
+
+        Ok(())
+    }
+
+    /// Set how many times a SYN or SYN|ACK is retransmitted before giving up the connection.
+    /// With the retransmission timeout doubling from 1 second, connect() fails after about
+    /// 2^(count + 1) - 1 seconds.
+    pub fn set_max_syn_retransmissions(&self, count: usize) {
+        self.max_syn_retransmissions.store(count, Ordering::Relaxed);
+    }
+
+    /// Set the size of the receive buffer of the sockets created afterwards, up to 1 GiB.
+    /// Connections accepted on a listening socket get the size the listening socket was created
+    /// with. The window scale is chosen so that the whole buffer can be advertised.
+    pub fn set_receive_buffer_size(&self, size: usize) {
+        self.receive_buffer_size.store(size, Ordering::Relaxed);
+    }
+
+    /// Set whether a RST is sent in response to a segment to a local address and port that no
+    /// socket of this stack uses. The raw socket receives every TCP segment arriving at the host,
+    /// including the ones for the kernel's connections and other stacks, which would be reset.
+    /// Disabled by default, so that only the ports in use by this stack are answered with a RST.
+    pub fn set_reset_unowned_ports(&self, enabled: bool) {
+        self.reset_unowned_ports.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set whether accept(), recv() and send() on the socket return WouldBlock instead of waiting.
+    /// A non-blocking send() sends as much of the data as the send window allows, and a
+    /// non-blocking close() does not wait for the connection termination to complete.
+    pub fn set_nonblocking(&self, socket_id: TcpSocketId, nonblocking: bool) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        socket.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Set how long recv() on the socket waits for data before returning WaitTimedOut.
+    /// None means forever.
+    pub fn set_read_timeout(
+        &self,
+        socket_id: TcpSocketId,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        socket.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Set how long send() on the socket waits for the send window to open before giving up.
+    /// It returns the number of bytes sent so far, or WaitTimedOut if nothing has been sent.
+    /// None means forever.
+    pub fn set_write_timeout(
+        &self,
+        socket_id: TcpSocketId,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        socket.write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self, socket_id: TcpSocketId) -> Result<Option<Duration>> {
+        let socket_table = self.sockets.read()?;
+        let socket = socket_table
+            .get(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        Ok(socket.read_timeout)
+    }
+
+    pub fn write_timeout(&self, socket_id: TcpSocketId) -> Result<Option<Duration>> {
+        let socket_table = self.sockets.read()?;
+        let socket = socket_table
+            .get(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        Ok(socket.write_timeout)
+    }
+
+    /// Subscribe to the events of all the sockets of the stack.
+    /// Every event notified after this call is sent to the returned channel in the order it
+    /// occurred. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> Result<Receiver<TcpEvent>> {
+        self.event_subscribers.subscribe()
+    }
+
     /// Create a listening socket.
     pub fn listen(&self, local_address: Ipv4Addr, local_port: u16) -> Result<TcpSocketId> {
+        self.check_health()?;
+
+        let mut socket_table = self.sockets.write()?;
+        if socket_table.values().any(|socket| {
+            socket.state == TcpState::Listen
+                && socket.local_port == local_port
+                && socket.local_address == local_address
+        }) {
+            return Err(Error::AddrInUse(local_address, local_port));
+        }
+
         let mut listening_socket = TcpSocket::new(
             local_address,
             local_port,
             // When a SYN packet is received, the remote address and port are assigned. See match arm TcpState::SynReceived in TcpSocket::receive_handler.
             Ipv4Addr::UNSPECIFIED,
             TCP_UNSPECIFIED_PORT,
+            Arc::clone(&self.event_subscribers),
         )?;
-        listening_socket.state = TcpState::Listen;
+        listening_socket.set_receive_buffer_size(self.receive_buffer_size.load(Ordering::Relaxed));
+        listening_socket.set_state(TcpState::Listen);
 
         debug!("{} : Created a new listening socket", listening_socket.id());
         info!(
@@ -78,10 +434,6 @@ impl TcpStream {
             listening_socket.local_address, listening_socket.local_port
         );
 
-        let mut socket_table = self
-            .sockets
-            .write()
-            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
         let socket_id = listening_socket.id();
         socket_table.insert(socket_id, listening_socket);
 
@@ -90,35 +442,159 @@ impl TcpStream {
 
     /// Accept a connection that is established on a listening socket.
     pub fn accept(&self, listening_socket_id: TcpSocketId) -> Result<TcpSocketId> {
-        self.wait_until(TcpEvent::ConnectionEstablished(listening_socket_id))?;
+        let (socket_id, _) = self.accept_until(listening_socket_id, None)?;
+        Ok(socket_id)
+    }
 
-        let mut socket_table = self
-            .sockets
-            .write()
-            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    /// Accept a connection in the same way as accept(), but return WaitTimedOut if no connection
+    /// is established within the timeout. The listening socket keeps listening.
+    pub fn accept_timeout(
+        &self,
+        listening_socket_id: TcpSocketId,
+        timeout: Duration,
+    ) -> Result<TcpSocketId> {
+        let (socket_id, _) =
+            self.accept_until(listening_socket_id, Some(Instant::now() + timeout))?;
+        Ok(socket_id)
+    }
 
-        let connected_socket_id = socket_table
-            .get_mut(&listening_socket_id)
-            .context(format!("No such listening socket {}", listening_socket_id))?
-            .connected_socket_queue
-            .pop_front()
-            .context(format!("No connected socket on {}", listening_socket_id))?;
+    /// Accept a connection, giving up when the deadline passes. Returns the connected socket with
+    /// its wait queue.
+    pub(crate) fn accept_until(
+        &self,
+        listening_socket_id: TcpSocketId,
+        deadline: Option<Instant>,
+    ) -> Result<(TcpSocketId, Arc<WaitQueue>)> {
+        loop {
+            let connected_socket_id =
+                self.wait_until(listening_socket_id, None, deadline, |listening_socket| {
+                    Self::dequeue_connection(listening_socket_id, listening_socket)
+                })?;
+            if let Some(wait_queue) = self.accepted(connected_socket_id)? {
+                return Ok((connected_socket_id, wait_queue));
+            }
+        }
+    }
+
+    /// Take a connection from the queue of the listening socket.
+    pub(crate) fn dequeue_connection(
+        listening_socket_id: TcpSocketId,
+        listening_socket: Option<&mut TcpSocket>,
+    ) -> Result<Option<TcpSocketId>> {
+        let listening_socket =
+            listening_socket.ok_or(Error::ConnectionClosed(listening_socket_id))?;
+        Ok(listening_socket.connected_socket_queue.pop_front())
+    }
+
+    /// Finish accepting the connection taken from the queue of the listening socket.
+    /// Returns the wait queue of the connected socket, or None if the connection has been reset
+    /// while it was in the queue, in which case the next one should be taken.
+    pub(crate) fn accepted(
+        &self,
+        connected_socket_id: TcpSocketId,
+    ) -> Result<Option<Arc<WaitQueue>>> {
+        let socket_table = self.sockets.read()?;
+        let Some(connected_socket) = socket_table.get(&connected_socket_id) else {
+            debug!(
+                "{} : Skipped the connection reset in the queue.",
+                connected_socket_id
+            );
+            return Ok(None);
+        };
 
         info!("{} : Accepted the connection request.", connected_socket_id);
 
-        Ok(connected_socket_id)
+        Ok(Some(Arc::clone(&connected_socket.wait_queue)))
     }
 
     /// Create a new TCP socket and try to connect to the remote address.
     pub fn connect(&self, remote_address: Ipv4Addr, remote_port: u16) -> Result<TcpSocketId> {
+        let (socket_id, _) = self.connect_until(remote_address, remote_port, None)?;
+        Ok(socket_id)
+    }
+
+    /// Connect in the same way as connect(), but abort the connection and return WaitTimedOut if
+    /// it is not established within the timeout.
+    pub fn connect_timeout(
+        &self,
+        remote_address: Ipv4Addr,
+        remote_port: u16,
+        timeout: Duration,
+    ) -> Result<TcpSocketId> {
+        let (socket_id, _) =
+            self.connect_until(remote_address, remote_port, Some(Instant::now() + timeout))?;
+        Ok(socket_id)
+    }
+
+    /// Connect, aborting the connection when the deadline passes. Returns the connected socket
+    /// with its wait queue.
+    pub(crate) fn connect_until(
+        &self,
+        remote_address: Ipv4Addr,
+        remote_port: u16,
+        deadline: Option<Instant>,
+    ) -> Result<(TcpSocketId, Arc<WaitQueue>)> {
+        let (socket_id, wait_queue) = self.start_connect(remote_address, remote_port)?;
+
+        // Since we sent the SYN packet as the first step of 3-way handshake, we wait for the receiving thread to receive a SYN|ACK and send an ACK.
+        let result = self.wait_until(socket_id, Some(&wait_queue), deadline, |socket| {
+            Self::is_connected(socket_id, socket)
+        });
+        if let Err(Error::WaitTimedOut(_)) = result {
+            // Stop retransmitting the SYN since the user has given up the connection. The socket
+            // may have been deleted in the meantime.
+            let _ = self.abort(socket_id);
+        }
+        result?;
+
+        info!("{} : Connection established", socket_id);
+
+        Ok((socket_id, wait_queue))
+    }
+
+    /// Create a new non-blocking TCP socket and send a SYN to the remote address.
+    /// Returns immediately with the socket in SYN-SENT. The socket becomes writable in poll() once
+    /// the connection is established. If the connection fails, the socket is deleted and poll()
+    /// reports it as hup. Returns ConnectionRefused if the connection has been refused before
+    /// returning.
+    pub fn connect_nonblocking(
+        &self,
+        remote_address: Ipv4Addr,
+        remote_port: u16,
+    ) -> Result<TcpSocketId> {
+        let (socket_id, wait_queue) = self.start_connect(remote_address, remote_port)?;
+
+        // A RST|ACK may have arrived already, in which case the socket has been deleted and the
+        // connection is reported as refused.
+        self.wait_until(socket_id, Some(&wait_queue), None, |socket| {
+            if let Some(socket) = socket {
+                socket.nonblocking = true;
+            }
+            Ok(Some(()))
+        })?;
+
+        Ok(socket_id)
+    }
+
+    /// Create a new TCP socket and send a SYN to the remote address without waiting for the
+    /// connection to be established. Returns the socket with its wait queue.
+    pub(crate) fn start_connect(
+        &self,
+        remote_address: Ipv4Addr,
+        remote_port: u16,
+    ) -> Result<(TcpSocketId, Arc<WaitQueue>)> {
+        self.check_health()?;
+
         let mut socket = TcpSocket::new(
             // FIXME: Find the local address that is assigned to the interface that has the route to the remote address.
             // Since IP is not implemented, it may be necessary to refer to the kernel routing table. rtnetlink?
-            "10.0.0.1".parse::<Ipv4Addr>()?,
+            Ipv4Addr::new(10, 0, 0, 1),
             self.select_unused_port()?,
             remote_address,
             remote_port,
+            Arc::clone(&self.event_subscribers),
         )?;
+        socket.set_receive_buffer_size(self.receive_buffer_size.load(Ordering::Relaxed));
 
         debug!("{} : Created a new TCP socket", socket.id());
         info!(
@@ -129,38 +605,436 @@ impl TcpStream {
         // SYN: First step of the three-way handshake.
         // FIXME: The iss must be a value that depends on a fictious 32-bit clock.
         // ref. 3.3. Sequence Numbers, Initial Sequence Number Selection
-        let initial_sequence_number = rand::thread_rng().gen_range(0..(1 << 31));
+        let initial_sequence_number = SeqNum::new(rand::thread_rng().gen());
         socket.snd.una = initial_sequence_number;
         socket.snd.nxt = initial_sequence_number + 1;
         socket.snd.iss = initial_sequence_number;
         // REVIEW: confirm with spec. snd.{up,wl1,wl2} and rcv.{nxt,up,iss} is initialized with 0. Is it okay?
 
-        debug!("{} : SYN sent.", socket.id());
-        socket.send_tcp_packet(socket.snd.iss, 0, TcpFlags::SYN, &[])?;
-        debug!(
-            "{} : State changed from {:?} to SynSent.",
-            socket.id(),
-            socket.state
-        );
-        socket.state = TcpState::SynSent;
+        // Hold the lock while sending the SYN so that the receiving thread cannot process the SYN|ACK
+        // before the socket is inserted into the table.
+        let mut socket_table = self.sockets.write()?;
 
-        let mut socket_table = self
-            .sockets
-            .write()
-            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        debug!("{} : SYN sent.", socket.id());
+        socket.send_tcp_packet(socket.snd.iss, SeqNum::new(0), TcpFlags::SYN, &[])?;
+        socket.set_state(TcpState::SynSent);
 
         let socket_id = socket.id();
+        let wait_queue = Arc::clone(&socket.wait_queue);
         socket_table.insert(socket.id(), socket);
 
+        Ok((socket_id, wait_queue))
+    }
+
+    /// Check if the three-way handshake has been completed.
+    pub(crate) fn is_connected(
+        socket_id: TcpSocketId,
+        socket: Option<&mut TcpSocket>,
+    ) -> Result<Option<()>> {
+        let socket = socket.ok_or(Error::ConnectionClosed(socket_id))?;
+        Ok(match socket.state {
+            TcpState::SynSent | TcpState::SynReceived => None,
+            _ => Some(()),
+        })
+    }
+
+    /// Send data on an established connection.
+    /// The data is divided into segments that fit in both the MSS and the send window. Blocks until
+    /// all the data has been sent, unless the socket is non-blocking.
+    pub fn send(&self, socket_id: TcpSocketId, buffer: &[u8]) -> Result<usize> {
+        self.send_on(socket_id, None, buffer)
+    }
+
+    /// Send data in the same way as send(), but only on the incarnation of the connection with
+    /// the wait queue, if given.
+    pub(crate) fn send_on(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+        buffer: &[u8],
+    ) -> Result<usize> {
+        let write_timeout = self
+            .sockets
+            .read()?
+            .get(&socket_id)
+            .filter(|socket| Self::is_incarnation(socket, wait_queue))
+            .and_then(|socket| socket.write_timeout);
+        let deadline = write_timeout.map(|timeout| Instant::now() + timeout);
+        let mut cursor = 0;
+
+        while cursor < buffer.len() {
+            // Wait for the remote host to acknowledge the data in flight if the window is full.
+            match self.wait_until(socket_id, wait_queue, deadline, |socket| {
+                Self::send_segments(socket_id, socket, &buffer[cursor..])
+            }) {
+                Ok(sent_size) => cursor += sent_size,
+                // A non-blocking socket sends only as much as the window allows, and a socket
+                // with a write timeout only as much as has been sent within it.
+                Err(Error::WouldBlock(_) | Error::WaitTimedOut(_)) if cursor > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(cursor)
+    }
+
+    /// Send as much of the data as the send window allows, divided into segments that fit in the
+    /// MSS. Returns None if the window is full.
+    pub(crate) fn send_segments(
+        socket_id: TcpSocketId,
+        socket: Option<&mut TcpSocket>,
+        buffer: &[u8],
+    ) -> Result<Option<usize>> {
+        let socket = socket.ok_or(Error::ConnectionClosed(socket_id))?;
+
+        // The connection started by connect_nonblocking() is still being established.
+        if matches!(socket.state, TcpState::SynSent | TcpState::SynReceived) {
+            return Ok(None);
+        }
+        if !matches!(socket.state, TcpState::Established | TcpState::CloseWait) {
+            return Err(Error::InvalidState(socket_id, socket.state));
+        }
+
+        let mut cursor = 0;
+        while cursor < buffer.len() {
+            let usable_window = socket.usable_window();
+            if usable_window == 0 {
+                break;
+            }
+
+            let segment_size = socket
+                .send_mss
+                .min(usable_window)
+                .min(buffer.len() - cursor);
+            socket.send_tcp_packet(
+                socket.snd.nxt,
+                socket.rcv.nxt,
+                TcpFlags::ACK,
+                &buffer[cursor..cursor + segment_size],
+            )?;
+            debug!("{} : Sent {} bytes of data.", socket_id, segment_size);
+            socket.snd.nxt += segment_size as u32;
+            cursor += segment_size;
+        }
+        // The persist timer probes the window while the rest waits for it to open.
+        socket.send_pending = cursor < buffer.len();
+
+        Ok((cursor > 0 || buffer.is_empty()).then_some(cursor))
+    }
+
+    /// Receive data from an established connection.
+    /// Blocks until at least one byte is available and returns the number of bytes read. Returns 0
+    /// once the remote host has closed the connection and all the data has been read.
+    pub fn recv(&self, socket_id: TcpSocketId, buffer: &mut [u8]) -> Result<usize> {
+        self.recv_on(socket_id, None, buffer)
+    }
+
+    /// Receive data in the same way as recv(), but only from the incarnation of the connection
+    /// with the wait queue, if given.
+    pub(crate) fn recv_on(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+        buffer: &mut [u8],
+    ) -> Result<usize> {
+        let read_timeout = self
+            .sockets
+            .read()?
+            .get(&socket_id)
+            .filter(|socket| Self::is_incarnation(socket, wait_queue))
+            .and_then(|socket| socket.read_timeout);
+        let deadline = read_timeout.map(|timeout| Instant::now() + timeout);
+
+        self.wait_until(socket_id, wait_queue, deadline, |socket| {
+            Self::receive_data(socket_id, socket, buffer)
+        })
+    }
+
+    /// Read the received data into the buffer. Returns None if no data has arrived yet.
+    pub(crate) fn receive_data(
+        socket_id: TcpSocketId,
+        socket: Option<&mut TcpSocket>,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>> {
+        // The connection has been closed and the socket has been deleted.
+        let Some(socket) = socket else {return Ok(Some(0));};
+
+        if !socket.receive_buffer.is_empty() {
+            let read_size = buffer.len().min(socket.receive_buffer.len());
+            for (dst, src) in buffer
+                .iter_mut()
+                .zip(socket.receive_buffer.drain(..read_size))
+            {
+                *dst = src;
+            }
+
+            let window_was_closed = socket.advertised_window() == 0;
+            socket.update_receive_window();
+            // Tell the remote host that the window has been opened again.
+            if window_was_closed {
+                debug!("{} : Window update sent.", socket_id);
+                socket.send_tcp_packet(socket.snd.nxt, socket.rcv.nxt, TcpFlags::ACK, &[])?;
+            }
+
+            return Ok(Some(read_size));
+        }
+
+        if socket.read_shutdown {
+            return Ok(Some(0));
+        }
+
+        // The remote host has sent a FIN and all the data has been read.
+        if matches!(
+            socket.state,
+            TcpState::CloseWait | TcpState::Closing | TcpState::LastAck | TcpState::TimeWait
+        ) {
+            return Ok(Some(0));
+        }
+
+        Ok(None)
+    }
+
+    /// Wait until at least one of the sockets is ready for the operations the user is interested
+    /// in, or the timeout elapses. The events are cleared and filled with the ready sockets up to
+    /// their capacity. A socket closed by the remote host or deleted is reported as hup regardless
+    /// of the interest. A timeout of None waits indefinitely, and a zero timeout never blocks.
+    pub fn poll(
+        &self,
+        events: &mut Events,
+        interests: &[(TcpSocketId, Interest)],
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        events.clear();
+
+        loop {
+            // Notifications are made with the socket table locked, so none can be missed between
+            // the check and the wait.
+            let generation = {
+                let socket_table = self.sockets.read()?;
+                self.check_health()?;
+
+                for &(socket_id, interest) in interests {
+                    let event = Event::of(socket_id, socket_table.get(&socket_id));
+                    if let Some(event) = event.filter(interest) {
+                        if !events.push(event) {
+                            break;
+                        }
+                    }
+                }
+                if !events.is_empty() {
+                    return Ok(());
+                }
+
+                self.readiness_queue.generation()?
+            };
+
+            debug!("Waiting for {} sockets to be ready...", interests.len());
+            match deadline {
+                None => self.readiness_queue.wait(generation)?,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(());
+                    }
+                    self.readiness_queue
+                        .wait_timeout(generation, deadline - now)?;
+                }
+            }
+        }
+    }
+
+    /// Close the connection.
+    /// Sends a FIN and blocks until the connection termination is completed, that is, the FIN is
+    /// acknowledged and the remote host has also closed its side. The socket is deleted when the
+    /// connection reaches the CLOSED state. The data arriving afterwards is discarded, and the
+    /// socket is also deleted if the remote host does not close its side within the FIN-WAIT-2
+    /// timeout.
+    pub fn close(&self, socket_id: TcpSocketId) -> Result<()> {
+        self.close_on(socket_id, None)
+    }
+
+    /// Close the connection in the same way as close(), but only the incarnation of the
+    /// connection with the wait queue, if given.
+    pub(crate) fn close_on(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+    ) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .filter(|socket| Self::is_incarnation(socket, wait_queue))
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+
+        info!("{} : Closing the connection.", socket_id);
+        match socket.state {
+            // No connection has been synchronized. Just delete the socket, and abort the
+            // connections of a listening socket that have not been accepted yet.
+            TcpState::Listen | TcpState::SynSent => {
+                let Some(socket) = self.delete_socket(
+                    &mut socket_table,
+                    socket_id,
+                    TcpEvent::ConnectionClosed(socket_id),
+                )?
+                else {
+                    return Ok(());
+                };
+                let pending_connections = Self::pending_connections(&socket_table, &socket);
+                // To allow abort() to acquire the lock.
+                drop(socket_table);
+                return self.abort_all(pending_connections);
+            }
+            TcpState::SynReceived | TcpState::Established | TcpState::CloseWait => {
+                socket.send_fin()?;
+            }
+            // A FIN has already been sent by shutdown().
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::LastAck => {}
+            TcpState::TimeWait | TcpState::Closed => return Ok(()),
+        }
+        self.shutdown_read(socket)?;
+
+        // The connection termination continues in the background.
+        if socket.nonblocking {
+            return Ok(());
+        }
+
         // To allow the receiving thread to acquire the lock.
         drop(socket_table);
+        self.wait_until(socket_id, wait_queue, None, |socket| {
+            Ok(match socket {
+                Some(socket) if socket.state != TcpState::TimeWait => None,
+                _ => Some(()),
+            })
+        })?;
 
-        // Since we sent the SYN packet as the first step of 3-way handshake, we wait for the receiving thread to receive a SYN|ACK and send an ACK.
-        self.wait_until(TcpEvent::ConnectionEstablished(socket_id))?;
+        info!("{} : Connection closed.", socket_id);
 
-        info!("{} : Connection established", socket_id);
+        Ok(())
+    }
 
-        Ok(socket_id)
+    /// Shut down the read, write, or both halves of the connection.
+    /// Shutting down the write half sends a FIN without waiting for the connection termination,
+    /// and the data sent by the remote host can still be read. After the read half is shut down,
+    /// recv() returns 0 and the data arriving afterwards is discarded. Once both halves are shut
+    /// down, the socket is deleted if the remote host does not close its side within the
+    /// FIN-WAIT-2 timeout.
+    pub fn shutdown(&self, socket_id: TcpSocketId, how: Shutdown) -> Result<()> {
+        self.shutdown_on(socket_id, None, how)
+    }
+
+    /// Shut down the connection in the same way as shutdown(), but only the incarnation of the
+    /// connection with the wait queue, if given.
+    pub(crate) fn shutdown_on(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+        how: Shutdown,
+    ) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .filter(|socket| Self::is_incarnation(socket, wait_queue))
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+
+        if matches!(
+            socket.state,
+            TcpState::Listen | TcpState::SynSent | TcpState::Closed
+        ) {
+            return Err(Error::InvalidState(socket_id, socket.state));
+        }
+
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.shutdown_read(socket)?;
+        }
+
+        // Nothing to do if a FIN has already been sent.
+        if matches!(how, Shutdown::Write | Shutdown::Both)
+            && matches!(
+                socket.state,
+                TcpState::SynReceived | TcpState::Established | TcpState::CloseWait
+            )
+        {
+            debug!("{} : Shut down the write half.", socket_id);
+            socket.send_fin()?;
+        }
+
+        Ok(())
+    }
+
+    /// Discard the received data, and the data arriving afterwards.
+    fn shutdown_read(&self, socket: &mut TcpSocket) -> Result<()> {
+        if socket.read_shutdown {
+            return Ok(());
+        }
+        debug!("{} : Shut down the read half.", socket.id());
+        socket.read_shutdown = true;
+        socket.receive_buffer.clear();
+        socket.update_receive_window();
+        // Wake up the reader to return 0.
+        self.notify_event(socket, TcpEvent::DataReadable(socket.id()))
+    }
+
+    /// Abort the connection.
+    /// A RST is sent if the connection is synchronized, all the queued data is discarded and the
+    /// users waiting on the socket get an error. The socket is deleted immediately. Aborting a
+    /// listening socket also aborts the connections that have not been accepted yet.
+    pub fn abort(&self, socket_id: TcpSocketId) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+
+        info!("{} : Aborting the connection.", socket_id);
+        socket.send_reset()?;
+        let Some(socket) = self.delete_socket(
+            &mut socket_table,
+            socket_id,
+            TcpEvent::ConnectionAborted(socket_id),
+        )?
+        else {
+            return Ok(());
+        };
+
+        let pending_connections = Self::pending_connections(&socket_table, &socket);
+        // To allow abort() to acquire the lock.
+        drop(socket_table);
+        self.abort_all(pending_connections)
+    }
+
+    /// Get the connections of a listening socket that have not been accepted yet, that is, the
+    /// ones in the connected socket queue and the ones still in the 3-way handshake.
+    fn pending_connections(
+        socket_table: &HashMap<TcpSocketId, TcpSocket>,
+        listening_socket: &TcpSocket,
+    ) -> Vec<TcpSocketId> {
+        let listening_socket_id = listening_socket.id();
+        let handshaking = socket_table
+            .values()
+            .filter(|socket| {
+                socket.state == TcpState::SynReceived
+                    && socket.listening_socket == Some(listening_socket_id)
+            })
+            .map(TcpSocket::id);
+        listening_socket
+            .connected_socket_queue
+            .iter()
+            .copied()
+            .chain(handshaking)
+            .collect()
+    }
+
+    /// Abort the connections. The ones that have already been deleted, e.g. reset by the remote
+    /// host, are skipped.
+    fn abort_all(&self, socket_ids: Vec<TcpSocketId>) -> Result<()> {
+        for socket_id in socket_ids {
+            match self.abort(socket_id) {
+                Ok(()) | Err(Error::NoSuchSocket(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
     }
 
     /// Select an unused local port in the range 49152..=65535.
@@ -184,47 +1058,308 @@ impl TcpStream {
             }
         }
 
-        Err(anyhow::anyhow!("No available local port"))
+        Err(Error::AddrNotAvailable)
     }
 
-    /// Wait until the specified event occurs.
-    fn wait_until(&self, event: TcpEvent) -> Result<()> {
-        let (lock, cvar) = &self.event_condvar;
-        let mut notified_event = lock.lock().map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    /// Block until the condition returns a value.
+    /// The condition is checked with the socket each time something happens on it, and with None
+    /// once the socket has been deleted by the connection termination. Returns an error if the
+    /// connection is reset, aborted, refused or timed out, or the stack fails while waiting, and
+    /// WaitTimedOut if the deadline passes first.
+    /// The wait queue identifies the incarnation of the connection to wait on. If it is not given,
+    /// it is taken when the socket is checked for the first time, and a socket that has already
+    /// been deleted is reported as NoSuchSocket.
+    fn wait_until<T>(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+        deadline: Option<Instant>,
+        mut condition: impl FnMut(Option<&mut TcpSocket>) -> Result<Option<T>>,
+    ) -> Result<T> {
+        let mut wait_queue = wait_queue.cloned();
 
-        debug!("{} waiting...", event);
         loop {
-            if let Some(e) = *notified_event {
-                if e == event {
-                    break;
+            match self.check_condition(socket_id, wait_queue.as_ref(), None, &mut condition)? {
+                Readiness::Ready(value) => return Ok(value),
+                Readiness::Pending(socket_wait_queue, generation) => {
+                    debug!("{} : Waiting...", socket_id);
+                    match deadline {
+                        None => socket_wait_queue.wait(generation)?,
+                        Some(deadline) => {
+                            let timeout = deadline.saturating_duration_since(Instant::now());
+                            if !socket_wait_queue.wait_timeout(generation, timeout)? {
+                                debug!("{} : Timed out waiting.", socket_id);
+                                return Err(Error::WaitTimedOut(socket_id));
+                            }
+                        }
+                    }
+                    wait_queue = Some(socket_wait_queue);
                 }
             }
+        }
+    }
+
+    /// Check the condition in the same way as wait_until() without blocking.
+    /// If the condition is not met, the waker of the task is woken up when something happens on
+    /// the socket.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn poll_until<T>(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: &Arc<WaitQueue>,
+        cx: &mut Context<'_>,
+        mut condition: impl FnMut(Option<&mut TcpSocket>) -> Result<Option<T>>,
+    ) -> Poll<Result<T>> {
+        match self.check_condition(
+            socket_id,
+            Some(wait_queue),
+            Some(cx.waker()),
+            &mut condition,
+        ) {
+            Ok(Readiness::Ready(value)) => Poll::Ready(Ok(value)),
+            Ok(Readiness::Pending(_, _)) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    /// Check if the socket is the incarnation of the connection with the wait queue. A socket with
+    /// the same four-tuple but another wait queue is a new incarnation, which means the one with
+    /// the wait queue has been deleted. Any socket matches if no wait queue is given.
+    fn is_incarnation(socket: &TcpSocket, wait_queue: Option<&Arc<WaitQueue>>) -> bool {
+        wait_queue.is_none_or(|wait_queue| Arc::ptr_eq(wait_queue, &socket.wait_queue))
+    }
+
+    /// Check the condition once with the socket table locked.
+    /// If it is not met, the waker is registered and the wait queue of the socket is returned with
+    /// the number of notifications so far. Notifications are made with the socket table locked,
+    /// so none can be missed between the check and the wait.
+    fn check_condition<T>(
+        &self,
+        socket_id: TcpSocketId,
+        wait_queue: Option<&Arc<WaitQueue>>,
+        waker: Option<&Waker>,
+        condition: &mut impl FnMut(Option<&mut TcpSocket>) -> Result<Option<T>>,
+    ) -> Result<Readiness<T>> {
+        let mut socket_table = self.sockets.write()?;
+        self.check_health()?;
+
+        let socket = socket_table
+            .get_mut(&socket_id)
+            .filter(|socket| Self::is_incarnation(socket, wait_queue));
+
+        let Some(socket) = socket else {
+            let wait_queue = wait_queue.ok_or(Error::NoSuchSocket(socket_id))?;
+            return Err(match wait_queue.closed_by()? {
+                Some(TcpEvent::ConnectionReset(_)) => Error::ConnectionReset(socket_id),
+                Some(TcpEvent::ConnectionAborted(_)) => Error::ConnectionAborted(socket_id),
+                Some(TcpEvent::ConnectionRefused(_)) => Error::ConnectionRefused(socket_id),
+                Some(TcpEvent::ConnectionTimedOut(_)) => Error::TimedOut(socket_id),
+                _ => {
+                    let value = condition(None)?.ok_or(Error::ConnectionClosed(socket_id))?;
+                    return Ok(Readiness::Ready(value));
+                }
+            });
+        };
+
+        if let Some(value) = condition(Some(socket))? {
+            return Ok(Readiness::Ready(value));
+        }
+
+        // Only threads are refused to wait. Tasks never block and are woken up by the waker.
+        if socket.nonblocking && waker.is_none() {
+            return Err(Error::WouldBlock(socket_id));
+        }
+
+        if let Some(waker) = waker {
+            socket.wait_queue.register_waker(waker)?;
+        }
+        let generation = socket.wait_queue.generation()?;
+        Ok(Readiness::Pending(
+            Arc::clone(&socket.wait_queue),
+            generation,
+        ))
+    }
+
+    /// Get the wait queue of the socket.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn wait_queue(&self, socket_id: TcpSocketId) -> Result<Arc<WaitQueue>> {
+        let socket_table = self.sockets.read()?;
+        let socket = socket_table
+            .get(&socket_id)
+            .ok_or(Error::NoSuchSocket(socket_id))?;
+        Ok(Arc::clone(&socket.wait_queue))
+    }
+
+    /// Notify the users waiting on the socket of the event.
+    /// Must be called with the socket table locked.
+    fn notify_event(&self, socket: &TcpSocket, event: TcpEvent) -> Result<()> {
+        debug!("{} event notified.", event);
+        self.event_subscribers.publish(event);
+        socket.wait_queue.notify()?;
+        self.readiness_queue.notify()
+    }
+
+    /// Put a connection opened passively into the queue of the listening socket once the 3-way
+    /// handshake is completed.
+    fn enqueue_connection(
+        &self,
+        socket_table: &mut HashMap<TcpSocketId, TcpSocket>,
+        listening_socket_id: TcpSocketId,
+        connected_socket_id: TcpSocketId,
+    ) -> Result<()> {
+        debug!(
+            "{} : Enqueued to the connected socket queue.",
+            connected_socket_id
+        );
+        let listening_socket = socket_table
+            .get_mut(&listening_socket_id)
+            .ok_or(Error::NoSuchSocket(listening_socket_id))?;
+        listening_socket
+            .connected_socket_queue
+            .push_back(connected_socket_id);
+        // By notifying here, the accept() can dequeue the connected socket and return it to the user.
+        debug!(
+            "{} : Notification sent, ready accept() to dequeue the connected socket.",
+            connected_socket_id
+        );
+        self.notify_event(
+            listening_socket,
+            TcpEvent::ConnectionEstablished(listening_socket_id),
+        )
+    }
 
-            notified_event = cvar
-                .wait(notified_event)
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+    /// Delete the socket and wake up the users waiting on it for the last time.
+    /// The event tells them why the socket has been deleted.
+    fn delete_socket(
+        &self,
+        socket_table: &mut HashMap<TcpSocketId, TcpSocket>,
+        socket_id: TcpSocketId,
+        event: TcpEvent,
+    ) -> Result<Option<TcpSocket>> {
+        let Some(mut socket) = socket_table.remove(&socket_id) else {return Ok(None);};
+        if socket.state != TcpState::Closed {
+            socket.set_state(TcpState::Closed);
         }
+        debug!("{} : Deleted the socket.", socket_id);
+        debug!("{} event notified.", event);
+        self.event_subscribers.publish(event);
+        socket.wait_queue.close(event)?;
+        self.readiness_queue.notify()?;
+
+        Ok(Some(socket))
+    }
+
+    /// Check the timers of the sockets every clock tick until the stack is dropped or shut down.
+    fn timer_handler(tcp: &Weak<Self>) -> Result<()> {
+        debug!("Timer thread started.");
 
-        debug!("{} event notified.", notified_event.unwrap());
+        loop {
+            std::thread::sleep(CLOCK_GRANULARITY);
 
-        *notified_event = None;
+            let Some(tcp) = tcp.upgrade() else {break;};
+            if tcp.health() == Health::Stopped {
+                break;
+            }
+            tcp.process_timers()?;
+        }
 
+        debug!("Timer thread stopped.");
         Ok(())
     }
 
-    /// Notify the specified event.
-    fn notify_event(&self, event: TcpEvent) -> Result<()> {
-        let (lock, cvar) = &self.event_condvar;
+    /// Retransmit the segments whose retransmission timer has expired, probe the zero windows and
+    /// delete the sockets whose TIME-WAIT timer has expired. A connection is given up if its SYN or SYN|ACK is not
+    /// acknowledged after the maximum number of retransmissions.
+    fn process_timers(&self) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let now = Instant::now();
+        let max_syn_retransmissions = self.max_syn_retransmissions.load(Ordering::Relaxed);
+        let mut timed_out_socket_ids = Vec::new();
+        for socket in socket_table.values_mut() {
+            if !socket.retransmission_timer.is_expired(now) {
+                continue;
+            }
+
+            // Give up establishing the connection if the SYN or SYN|ACK has already been
+            // retransmitted as many times as allowed.
+            if matches!(socket.state, TcpState::SynSent | TcpState::SynReceived)
+                && socket
+                    .retransmission_queue
+                    .front()
+                    .is_some_and(|entry| entry.transmission_count > max_syn_retransmissions)
+            {
+                timed_out_socket_ids.push(socket.id());
+                continue;
+            }
+
+            // A failure to retransmit is retried when the timer expires again.
+            if let Err(e) = socket.retransmit() {
+                warn!("{} : Failed to retransmit: {}", socket.id(), e);
+            }
+        }
+
+        // A failure to send a window probe is retried at the next clock tick.
+        for socket in socket_table.values_mut() {
+            if let Err(e) = socket.process_persist_timer(now) {
+                warn!("{} : Failed to send a window probe: {}", socket.id(), e);
+            }
+        }
+
+        for socket_id in timed_out_socket_ids {
+            info!("{} : Connection timed out.", socket_id);
+            self.delete_socket(
+                &mut socket_table,
+                socket_id,
+                TcpEvent::ConnectionTimedOut(socket_id),
+            )?;
+        }
 
-        let mut notified_event = lock.lock().map_err(|e| anyhow::anyhow!("{:?}", e))?;
-        *notified_event = Some(event);
-        cvar.notify_all();
+        // Delete the sockets that have been in TIME-WAIT for 2MSL.
+        let expired_socket_ids: Vec<_> = socket_table
+            .values()
+            .filter(|socket| {
+                socket
+                    .time_wait_expires_at
+                    .is_some_and(|expires_at| expires_at <= now)
+            })
+            .map(|socket| socket.id())
+            .collect();
+        for socket_id in expired_socket_ids {
+            self.delete_socket(
+                &mut socket_table,
+                socket_id,
+                TcpEvent::ConnectionClosed(socket_id),
+            )?;
+        }
+
+        // Stop waiting for the FIN of the remote host if both halves have been shut down, since
+        // nobody can use the connection any more. The timer starts at the first clock tick.
+        let mut expired_socket_ids = Vec::new();
+        for socket in socket_table.values_mut() {
+            if socket.state != TcpState::FinWait2 || !socket.read_shutdown {
+                continue;
+            }
+            match socket.fin_wait_2_expires_at {
+                None => socket.fin_wait_2_expires_at = Some(now + TCP_FIN_WAIT_2_TIMEOUT),
+                Some(expires_at) if expires_at <= now => expired_socket_ids.push(socket.id()),
+                Some(_) => {}
+            }
+        }
+        for socket_id in expired_socket_ids {
+            info!("{} : FIN-WAIT-2 timed out.", socket_id);
+            self.delete_socket(
+                &mut socket_table,
+                socket_id,
+                TcpEvent::ConnectionClosed(socket_id),
+            )?;
+        }
 
         Ok(())
     }
 
-    /// Receive IPv4 packets and process them as TCP packets.
-    pub fn receive_handler(&self) -> Result<()> {
+    /// Receive IPv4 packets and process them as TCP packets until the stack is dropped or shut
+    /// down.
+    fn receive_handler(tcp: &Weak<Self>) -> Result<()> {
         debug!("Recieving thread started.");
         info!("Started receiving arriving IP packets.");
         let (_, mut receiver) = transport::transport_channel(
@@ -244,7 +1379,31 @@ impl TcpStream {
         // 4. Process the TCP packet.
         loop {
             // 1. Receive a IP packet.
-            let Ok((packet, remote_address)) = ipv4_packet_iter.next() else {continue;};
+            // Wake up periodically to see if the stack has been dropped or shut down.
+            let received = ipv4_packet_iter.next_with_timeout(RECEIVE_TIMEOUT);
+            let Some(tcp) = tcp.upgrade() else {break;};
+            if tcp.health() == Health::Stopped {
+                break;
+            }
+            let (packet, remote_address) = match received {
+                Ok(Some(received)) => received,
+                // Nothing has arrived within the timeout.
+                Ok(None) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    debug!("Retrying to receive a packet: {}", e);
+                    continue;
+                }
+                // The raw socket is no longer usable, so the stack fails.
+                Err(e) => {
+                    error!("Failed to receive a packet: {}", e);
+                    return Err(e.into());
+                }
+            };
             debug!("Received a IPv4 packet {:X?}.", &packet);
 
             // Ignore IPv6 packets.
@@ -271,180 +1430,710 @@ impl TcpStream {
             debug!("Verified the TCP packet {:X?}", &packet);
 
             // 4. Process the TCP packet.
-            let mut socket_table = self.sockets.write().unwrap();
-            // This socket is either a connected socket or a listening socket.
-            let socket = match socket_table.get_mut(&TcpSocketId {
-                local_address,
-                local_port: packet.destination_port(),
-                remote_address,
-                remote_port: packet.source_port(),
-            }) {
-                // Connected socket.
-                Some(connected_socket) => connected_socket,
-                None => {
-                    match socket_table.get_mut(&TcpSocketId {
-                        local_address,
-                        local_port: packet.destination_port(),
-                        remote_address: Ipv4Addr::UNSPECIFIED,
-                        remote_port: TCP_UNSPECIFIED_PORT,
-                    }) {
-                        // Listening socket.
-                        Some(listening_socket) => listening_socket,
-                        // No corresponding socket.
-                        None => continue,
+            // An error in processing a packet only affects the packet. The stack keeps running
+            // unless the socket table is no longer usable.
+            if let Err(e) = tcp.process_packet(local_address, remote_address, &packet) {
+                if let Error::LockPoisoned = e {
+                    return Err(e);
+                }
+                warn!("Failed to process the TCP packet {:X?}: {}", &packet, e);
+            }
+        }
+
+        debug!("Receiving thread stopped.");
+        Ok(())
+    }
+
+    /// Process a TCP packet arriving at the local address from the remote address.
+    fn process_packet(
+        &self,
+        local_address: Ipv4Addr,
+        remote_address: Ipv4Addr,
+        packet: &TcpPacket,
+    ) -> Result<()> {
+        let mut socket_table = self.sockets.write()?;
+        let connected_socket_id = TcpSocketId {
+            local_address,
+            local_port: packet.destination_port(),
+            remote_address,
+            remote_port: packet.source_port(),
+        };
+
+        // A new SYN whose sequence number is greater than any seen on the previous incarnation
+        // of the connection reopens a connection in TIME-WAIT. The socket in TIME-WAIT is
+        // deleted and the SYN is handed to the listening socket.
+        // ref. RFC 6191, RFC 1122 4.2.2.13.
+        if let Some(time_wait_socket) = socket_table.get(&connected_socket_id) {
+            if time_wait_socket.state == TcpState::TimeWait
+                && packet.flags() == TcpFlags::SYN
+                && time_wait_socket.rcv.nxt < packet.sequence_number()
+            {
+                debug!(
+                    "{} : New SYN received in TIME-WAIT, reusing the connection.",
+                    connected_socket_id
+                );
+                self.delete_socket(
+                    &mut socket_table,
+                    connected_socket_id,
+                    TcpEvent::ConnectionClosed(connected_socket_id),
+                )?;
+            }
+        }
+
+        // This socket is either a connected socket or a listening socket.
+        let socket = match socket_table.get_mut(&connected_socket_id) {
+            // Connected socket.
+            Some(connected_socket) => connected_socket,
+            None => {
+                match socket_table.get_mut(&TcpSocketId {
+                    local_address,
+                    local_port: packet.destination_port(),
+                    remote_address: Ipv4Addr::UNSPECIFIED,
+                    remote_port: TCP_UNSPECIFIED_PORT,
+                }) {
+                    // Listening socket.
+                    Some(listening_socket) => listening_socket,
+                    // No corresponding socket. The segment may be for someone else sharing the host,
+                    // so the port is only treated as closed if this stack uses it.
+                    None => {
+                        let owned = socket_table.keys().any(|socket_id| {
+                            socket_id.local_address == local_address
+                                && socket_id.local_port == packet.destination_port()
+                        });
+                        if owned || self.reset_unowned_ports.load(Ordering::Relaxed) {
+                            self.send_reset(local_address, remote_address, packet)?;
+                        } else {
+                            debug!(
+                                "{} : Ignored a segment to a port not used by this stack.",
+                                connected_socket_id
+                            );
+                        }
+                        return Ok(());
                     }
                 }
-            };
+            }
+        };
 
-            match socket.state {
-                TcpState::Listen => {
-                    if packet.flags() == TcpFlags::ACK {
-                        // Originally send RST.
-                        continue;
+        match socket.state {
+            TcpState::Listen => {
+                if packet.flags() & TcpFlags::RST != 0 {
+                    return Ok(());
+                }
+
+                // Nothing has been sent on the listening socket, so any ACK is unacceptable.
+                if packet.flags() & TcpFlags::ACK != 0 {
+                    debug!("{} : ACK received on the listening socket.", socket.id());
+                    self.send_reset(local_address, remote_address, packet)?;
+                    return Ok(());
+                }
+
+                // In response to a received SYN on the listening socket, send SYN|ACK.
+                if packet.flags() == TcpFlags::SYN {
+                    info!(
+                        "{} : Received a connection request on the listening socket.",
+                        socket.id()
+                    );
+                    debug!("{} : SYN received on the listening socket.", socket.id());
+                    let listening_socket = &*socket;
+                    let remote_port = packet.source_port();
+                    // The socket transitions to a 'connected socket' upon receiving an ACK.
+                    let mut connected_socket = TcpSocket::new(
+                        listening_socket.local_address,
+                        listening_socket.local_port,
+                        remote_address,
+                        remote_port,
+                        Arc::clone(&self.event_subscribers),
+                    )?;
+                    connected_socket.set_state(TcpState::SynReceived);
+                    // TODO: Output the current state in debug!s.
+                    debug!(
+                        "{} : Created a new connected socket.",
+                        connected_socket.id(),
+                    );
+
+                    connected_socket.rcv.nxt = packet.sequence_number() + 1;
+                    connected_socket.rcv.irs = packet.sequence_number();
+
+                    connected_socket.snd.iss = SeqNum::new(rand::thread_rng().gen());
+                    connected_socket.snd.una = connected_socket.snd.iss;
+                    connected_socket.snd.nxt = connected_socket.snd.iss + 1;
+                    connected_socket.snd.wnd = packet.window_size() as u32;
+                    connected_socket.set_receive_buffer_size(listening_socket.receive_buffer_size);
+                    connected_socket.process_syn_options(packet);
+
+                    debug!("{} : SYN|ACK sent.", connected_socket.id());
+                    connected_socket.send_tcp_packet(
+                        connected_socket.snd.iss,
+                        connected_socket.rcv.nxt,
+                        TcpFlags::SYN | TcpFlags::ACK,
+                        &[],
+                    )?;
+
+                    connected_socket.listening_socket = Some(listening_socket.id());
+                    socket_table.insert(connected_socket.id(), connected_socket);
+                }
+            }
+            TcpState::SynSent => {
+                // SEG.ACK =< ISS or SEG.ACK > SND.NXT.
+                if packet.flags() & TcpFlags::ACK != 0
+                    && (packet.acknowledgment_number() <= socket.snd.iss
+                        || socket.snd.nxt < packet.acknowledgment_number())
+                {
+                    debug!("{} : Unacceptable ACK received.", socket.id());
+                    self.send_reset(local_address, remote_address, packet)?;
+                    return Ok(());
+                }
+
+                // A RST is valid only if it acknowledges our SYN.
+                if packet.flags() & TcpFlags::RST != 0 {
+                    if packet.flags() & TcpFlags::ACK != 0 {
+                        let socket_id = socket.id();
+                        info!("{} : Connection refused.", socket_id);
+                        self.delete_socket(
+                            &mut socket_table,
+                            socket_id,
+                            TcpEvent::ConnectionRefused(socket_id),
+                        )?;
                     }
+                    return Ok(());
+                }
 
-                    // In response to a received SYN on the listening socket, send SYN|ACK.
-                    if packet.flags() == TcpFlags::SYN {
-                        info!(
-                            "{} : Received a connection request on the listening socket.",
-                            socket.id()
-                        );
-                        debug!("{} : SYN received on the listening socket.", socket.id());
-                        let listening_socket = &*socket;
-                        let remote_port = packet.source_port();
-                        // The socket transitions to a 'connected socket' upon receiving an ACK.
-                        let mut connected_socket = TcpSocket::new(
-                            listening_socket.local_address,
-                            listening_socket.local_port,
-                            remote_address,
-                            remote_port,
+                if packet.flags() == TcpFlags::SYN | TcpFlags::ACK
+                    // SND.UNA <= SEG.ACK <= SND.NXT.
+                    && socket.snd.una <= packet.acknowledgment_number()
+                    && packet.acknowledgment_number() <= socket.snd.nxt
+                {
+                    debug!("{} : SYN|ACK received.", socket.id());
+                    // Processing for <-- ACK.
+                    socket.acknowledge(packet);
+                    socket.snd.wnd = packet.window_size() as u32;
+                    socket.snd.wl1 = packet.sequence_number();
+                    socket.snd.wl2 = packet.acknowledgment_number();
+
+                    // Processing for <-- SYN
+                    socket.rcv.nxt = packet.sequence_number() + 1;
+                    socket.rcv.irs = packet.sequence_number();
+                    socket.process_syn_options(packet);
+
+                    // Basic 3-way handshake.
+                    // see Section 3.4. Establishing a Connection - Figure 8.
+                    if socket.snd.iss < socket.snd.una {
+                        debug!("{} : ACK sent.", socket.id());
+                        socket.send_tcp_packet(
+                            socket.snd.nxt,
+                            socket.rcv.nxt,
+                            TcpFlags::ACK,
+                            &[],
                         )?;
-                        debug!(
-                            "{} : State changed from {:?} to SynReceived.",
-                            connected_socket.id(),
-                            connected_socket.state
-                        );
-                        connected_socket.state = TcpState::SynReceived;
-                        // TODO: Output the current state in debug!s.
-                        debug!(
-                            "{} : Created a new connected socket.",
-                            connected_socket.id(),
-                        );
-
-                        connected_socket.rcv.nxt = packet.sequence_number() + 1;
-                        connected_socket.rcv.irs = packet.sequence_number();
-
-                        connected_socket.snd.iss = rand::thread_rng().gen_range(0..(1 << 31));
-                        connected_socket.snd.una = connected_socket.snd.iss;
-                        connected_socket.snd.nxt = connected_socket.snd.iss + 1;
-                        connected_socket.snd.wnd = packet.window_size();
-
-                        debug!("{} : SYN|ACK sent.", connected_socket.id());
-                        connected_socket.send_tcp_packet(
-                            connected_socket.snd.iss,
-                            connected_socket.rcv.nxt,
-                            TcpFlags::SYN | TcpFlags::ACK,
+                        socket.set_state(TcpState::Established);
+                        self.notify_event(socket, TcpEvent::ConnectionEstablished(socket.id()))?;
+                    }
+                    // Simultaneous 3-way handshake.
+                    // see Section 3.4. Establishing a Connection - Figure 9.
+                    else {
+                        socket.set_state(TcpState::SynReceived);
+                        socket.send_tcp_packet(
+                            socket.snd.iss,
+                            socket.rcv.nxt,
+                            TcpFlags::ACK,
                             &[],
                         )?;
-
-                        connected_socket.listening_socket = Some(listening_socket.id());
-                        socket_table.insert(connected_socket.id(), connected_socket);
                     }
                 }
-                TcpState::SynSent => {
-                    if packet.flags() == TcpFlags::SYN | TcpFlags::ACK
-                        // SND.UNA <= SEG.ACK <= SND.NXT.
-                        && socket.snd.una <= packet.acknowledgment_number()
-                        && packet.acknowledgment_number() <= socket.snd.nxt
-                    {
-                        debug!("{} : SYN|ACK received.", socket.id());
-                        // Processing for <-- ACK.
-                        socket.snd.una = packet.acknowledgment_number();
-                        socket.snd.wnd = packet.window_size();
-
-                        // Processing for <-- SYN
-                        socket.rcv.nxt = packet.sequence_number() + 1;
-                        socket.rcv.irs = packet.sequence_number();
-
-                        // Basic 3-way handshake.
-                        // see Section 3.4. Establishing a Connection - Figure 8.
-                        if socket.snd.iss < socket.snd.una {
-                            debug!("{} : ACK sent.", socket.id());
-                            socket.send_tcp_packet(
-                                socket.snd.nxt,
-                                socket.rcv.nxt,
-                                TcpFlags::ACK,
-                                &[],
-                            )?;
-                            debug!(
-                                "{} : State changed from {:?} to Established.",
-                                socket.id(),
-                                socket.state
-                            );
-                            socket.state = TcpState::Established;
-                            self.notify_event(TcpEvent::ConnectionEstablished(socket.id()))?;
-                        }
-                        // Simultaneous 3-way handshake.
-                        // see Section 3.4. Establishing a Connection - Figure 9.
-                        else {
-                            debug!(
-                                "{} : State changed from {:?} to SynReceived.",
-                                socket.id(),
-                                socket.state
-                            );
-                            socket.state = TcpState::SynReceived;
-                            socket.send_tcp_packet(
-                                socket.snd.iss,
-                                socket.rcv.nxt,
-                                TcpFlags::ACK,
-                                &[],
+            }
+            TcpState::SynReceived => {
+                let connected_socket = socket;
+
+                // A connection opened passively returns to the listening socket, that is, only
+                // the connected socket is deleted. One opened actively is refused.
+                if packet.flags() & TcpFlags::RST != 0 {
+                    if connected_socket.is_in_receive_window(packet.sequence_number()) {
+                        let connected_socket_id = connected_socket.id();
+                        if connected_socket.listening_socket.is_some() {
+                            self.delete_reset_connection(&mut socket_table, connected_socket_id)?;
+                        } else {
+                            info!("{} : Connection refused.", connected_socket_id);
+                            self.delete_socket(
+                                &mut socket_table,
+                                connected_socket_id,
+                                TcpEvent::ConnectionRefused(connected_socket_id),
                             )?;
                         }
                     }
+                    return Ok(());
                 }
-                TcpState::SynReceived => {
-                    let connected_socket = socket;
 
-                    if packet.flags() == TcpFlags::ACK
-                        && connected_socket.snd.una <= packet.acknowledgment_number()
-                        && packet.acknowledgment_number() <= connected_socket.snd.nxt
-                    {
-                        debug!("{} : ACK received.", connected_socket.id());
-                        connected_socket.rcv.nxt = packet.sequence_number() + 1;
-                        connected_socket.snd.una = packet.acknowledgment_number();
+                if !connected_socket.process_timestamps(packet)? {
+                    return Ok(());
+                }
 
-                        debug!(
-                            "{} : State changed from {:?} to Established.",
-                            connected_socket.id(),
-                            connected_socket.state
-                        );
-                        connected_socket.state = TcpState::Established;
+                // Not SND.UNA <= SEG.ACK <= SND.NXT.
+                if packet.flags() & TcpFlags::ACK != 0
+                    && !(connected_socket.snd.una <= packet.acknowledgment_number()
+                        && packet.acknowledgment_number() <= connected_socket.snd.nxt)
+                {
+                    debug!("{} : Unacceptable ACK received.", connected_socket.id());
+                    self.send_reset(local_address, remote_address, packet)?;
+                    return Ok(());
+                }
 
-                        let connected_socket_id = connected_socket.id();
+                // The ACK may come with data (PSH) and a FIN.
+                if packet.flags() & TcpFlags::ACK != 0
+                    && packet.flags() & (TcpFlags::SYN | TcpFlags::RST) == 0
+                    && connected_socket.snd.una <= packet.acknowledgment_number()
+                    && packet.acknowledgment_number() <= connected_socket.snd.nxt
+                {
+                    debug!("{} : ACK received.", connected_socket.id());
+                    connected_socket.acknowledge(packet);
+                    connected_socket.snd.wnd = connected_socket.scaled_window(packet);
+                    connected_socket.snd.wl1 = packet.sequence_number();
+                    connected_socket.snd.wl2 = packet.acknowledgment_number();
 
-                        if let Some(listening_socket_id) = connected_socket.listening_socket {
-                            debug!(
-                                "{} : Enqueued to the connected socket queue.",
-                                connected_socket.id()
-                            );
-                            let listening_socket =
-                                socket_table.get_mut(&listening_socket_id).unwrap();
-                            listening_socket
-                                .connected_socket_queue
-                                .push_back(connected_socket_id);
-                            // By notifying here, the accept() can dequeue the connected socket and return it to the user.
-                            debug!(
-                                "{} : Notification sent, ready accept() to dequeue the connected socket.",
-                                connected_socket_id
-                            );
-                            self.notify_event(TcpEvent::ConnectionEstablished(
-                                listening_socket_id,
-                            ))?;
-                        }
+                    connected_socket.set_state(TcpState::Established);
+
+                    // The ACK completing the handshake may carry data and a FIN.
+                    self.process_segment_text(connected_socket, packet)?;
+
+                    let connected_socket_id = connected_socket.id();
+
+                    if let Some(listening_socket_id) = connected_socket.listening_socket {
+                        self.enqueue_connection(
+                            &mut socket_table,
+                            listening_socket_id,
+                            connected_socket_id,
+                        )?;
                     }
                 }
-                _ => todo!("no implentation for state {:?}", socket.state),
             }
+            TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::CloseWait
+            | TcpState::Closing
+            | TcpState::LastAck => {
+                if packet.flags() & TcpFlags::RST != 0 {
+                    if socket.is_in_receive_window(packet.sequence_number()) {
+                        let socket_id = socket.id();
+                        self.delete_reset_connection(&mut socket_table, socket_id)?;
+                    }
+                    return Ok(());
+                }
+
+                if !socket.process_timestamps(packet)? {
+                    return Ok(());
+                }
+
+                // A SYN, e.g. a retransmitted SYN|ACK whose ACK has been lost, and an old segment
+                // without text are not acceptable. The ACK sent tells the remote host what we
+                // expect next.
+                // ref. 3.9. Event Processing, SEGMENT ARRIVES, first check sequence number.
+                if packet.flags() & TcpFlags::SYN != 0
+                    || (packet.segment_length() == 0 && packet.sequence_number() < socket.rcv.nxt)
+                {
+                    debug!("{} : Unacceptable segment received, ACK sent.", socket.id());
+                    socket.send_tcp_packet(socket.snd.nxt, socket.rcv.nxt, TcpFlags::ACK, &[])?;
+                    return Ok(());
+                }
+
+                if self.process_acknowledgment(socket, packet)? {
+                    self.process_segment_text(socket, packet)?;
+                }
+
+                if socket.state == TcpState::TimeWait {
+                    debug!("{} : TIME-WAIT timer started.", socket.id());
+                    socket.time_wait_expires_at = Some(Instant::now() + 2 * TCP_MSL);
+                    socket.retransmission_timer.stop();
+                    // The connection is closed from the user's point of view, so the user
+                    // waiting in close() is woken up. ConnectionClosed is notified when the
+                    // socket is deleted after 2MSL.
+                    socket.wait_queue.notify()?;
+                    self.readiness_queue.notify()?;
+                }
+                if socket.state == TcpState::Closed {
+                    let socket_id = socket.id();
+                    self.delete_socket(
+                        &mut socket_table,
+                        socket_id,
+                        TcpEvent::ConnectionClosed(socket_id),
+                    )?;
+                }
+            }
+            TcpState::TimeWait => {
+                // Protection against TIME-WAIT assassination. ref. RFC 1337.
+                if packet.flags() & TcpFlags::RST != 0 {
+                    debug!("{} : Ignored a RST in TIME-WAIT.", socket.id());
+                    return Ok(());
+                }
+
+                if !socket.process_timestamps(packet)? {
+                    return Ok(());
+                }
+
+                // The only thing that can arrive is a retransmission of the remote FIN, which
+                // means our ACK has been lost. Acknowledge it and restart the 2MSL timeout.
+                if packet.flags() & TcpFlags::FIN != 0 {
+                    debug!(
+                        "{} : Retransmitted FIN received in TIME-WAIT, ACK sent.",
+                        socket.id()
+                    );
+                    socket.send_tcp_packet(socket.snd.nxt, socket.rcv.nxt, TcpFlags::ACK, &[])?;
+                    socket.time_wait_expires_at = Some(Instant::now() + 2 * TCP_MSL);
+                }
+            }
+            // A deleted socket is no longer in the socket table.
+            TcpState::Closed => {}
+        }
+
+        Ok(())
+    }
+
+    /// Delete a connection that has been reset and wake up the users waiting on it.
+    /// All the data in the send and receive buffers is discarded. A connection that has not been
+    /// accepted yet is left in the accept queue so that accept() tells the user it has been reset.
+    fn delete_reset_connection(
+        &self,
+        socket_table: &mut HashMap<TcpSocketId, TcpSocket>,
+        socket_id: TcpSocketId,
+    ) -> Result<()> {
+        info!("{} : Connection reset.", socket_id);
+        self.delete_socket(
+            socket_table,
+            socket_id,
+            TcpEvent::ConnectionReset(socket_id),
+        )?;
+
+        Ok(())
+    }
+
+    /// Send a RST in response to a segment that does not belong to any connection.
+    /// ref. 3.4. Establishing a connection, Reset Generation.
+    fn send_reset(
+        &self,
+        local_address: Ipv4Addr,
+        remote_address: Ipv4Addr,
+        packet: &TcpPacket,
+    ) -> Result<()> {
+        // A RST is never sent in response to a RST.
+        if packet.flags() & TcpFlags::RST != 0 {
+            return Ok(());
         }
+
+        let socket_id = TcpSocketId {
+            local_address,
+            local_port: packet.destination_port(),
+            remote_address,
+            remote_port: packet.source_port(),
+        };
+        let (sequence_number, acknowledgment_number, flags) = if packet.flags() & TcpFlags::ACK != 0
+        {
+            // <SEQ=SEG.ACK><CTL=RST>
+            debug!("{} : RST sent.", socket_id);
+            (
+                packet.acknowledgment_number(),
+                SeqNum::new(0),
+                TcpFlags::RST,
+            )
+        } else {
+            // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+            debug!("{} : RST|ACK sent.", socket_id);
+            (
+                SeqNum::new(0),
+                packet.sequence_number() + packet.segment_length(),
+                TcpFlags::RST | TcpFlags::ACK,
+            )
+        };
+
+        let mut reset = TcpPacket::new(&[], 0);
+        reset.set_source_port(socket_id.local_port);
+        reset.set_destination_port(socket_id.remote_port);
+        reset.set_sequence_number(sequence_number);
+        reset.set_acknowledgment_number(acknowledgment_number);
+        reset.set_flags(flags);
+        reset.set_window_size(0);
+        reset.set_checksum(pnet::util::ipv4_checksum(
+            reset.packet(),
+            8,
+            &[],
+            &local_address,
+            &remote_address,
+            IpNextHeaderProtocols::Tcp,
+        ));
+
+        let mut sender = self.reset_sender.lock()?;
+        let sender = match &mut *sender {
+            Some(sender) => sender,
+            None => sender.insert(
+                transport::transport_channel(
+                    1 << 16,
+                    TransportChannelType::Layer4(TransportProtocol::Ipv4(
+                        IpNextHeaderProtocols::Tcp,
+                    )),
+                )?
+                .0,
+            ),
+        };
+        sender.send_to(&reset, IpAddr::V4(remote_address))?;
+
+        Ok(())
+    }
+
+    /// Process the ACK field of a segment arriving in a synchronized state.
+    /// Returns false if the segment must be dropped.
+    fn process_acknowledgment(&self, socket: &mut TcpSocket, packet: &TcpPacket) -> Result<bool> {
+        if packet.flags() & TcpFlags::ACK == 0 {
+            debug!("{} : Dropped a segment without ACK.", socket.id());
+            return Ok(false);
+        }
+
+        let acknowledgment_number = packet.acknowledgment_number();
+
+        // The ACK acknowledges something not yet sent.
+        if socket.snd.nxt < acknowledgment_number {
+            debug!("{} : ACK for unsent data received, ACK sent.", socket.id());
+            socket.send_tcp_packet(socket.snd.nxt, socket.rcv.nxt, TcpFlags::ACK, &[])?;
+            return Ok(false);
+        }
+
+        // Whether the usable window may have grown.
+        let mut send_space_available = false;
+
+        // SND.UNA < SEG.ACK <= SND.NXT.
+        let acknowledged = socket.snd.una < acknowledgment_number;
+        if acknowledged {
+            debug!(
+                "{} : Acknowledged up to {}.",
+                socket.id(),
+                acknowledgment_number
+            );
+            socket.acknowledge(packet);
+            send_space_available = true;
+
+            // Our FIN is the last segment sent in these states, so it has been acknowledged if
+            // everything has been acknowledged.
+            if socket.snd.una == socket.snd.nxt {
+                match socket.state {
+                    TcpState::FinWait1 => socket.set_state(TcpState::FinWait2),
+                    TcpState::Closing => socket.set_state(TcpState::TimeWait),
+                    TcpState::LastAck => socket.set_state(TcpState::Closed),
+                    _ => {}
+                }
+            }
+        }
+
+        // Update the send window unless the segment is older than the one used for the last update.
+        // ref. 3.9. Event Processing, SEGMENT ARRIVES, fifth check the ACK field.
+        if socket.snd.una <= acknowledgment_number
+            && (socket.snd.wl1 < packet.sequence_number()
+                || (socket.snd.wl1 == packet.sequence_number()
+                    && socket.snd.wl2 <= acknowledgment_number))
+        {
+            let window = socket.scaled_window(packet);
+            send_space_available |= socket.snd.wnd < window;
+            socket.snd.wnd = window;
+            socket.snd.wl1 = packet.sequence_number();
+            socket.snd.wl2 = acknowledgment_number;
+        }
+
+        // An old ACK says nothing about the segments lost.
+        if socket.snd.una <= acknowledgment_number {
+            socket.process_sack(packet, acknowledged)?;
+            // The ACK may have taken segments out of the network, which lets more new data be
+            // sent during the loss recovery.
+            send_space_available |= socket.recovery.in_recovery;
+        }
+
+        if send_space_available {
+            self.notify_event(socket, TcpEvent::SendSpaceAvailable(socket.id()))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Process the segment text and the FIN of a segment arriving in a synchronized state.
+    fn process_segment_text(&self, socket: &mut TcpSocket, packet: &TcpPacket) -> Result<()> {
+        let payload = packet.payload();
+        let fin = packet.flags() & TcpFlags::FIN != 0;
+        if payload.is_empty() && !fin {
+            return Ok(());
+        }
+
+        // Once a FIN has been received, no more text is expected.
+        let receiving = matches!(
+            socket.state,
+            TcpState::SynReceived | TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        );
+
+        // The segment starting at RCV.NXT is accepted, and so is the data received out of order
+        // that it makes contiguous. A segment beyond RCV.NXT is kept until the gap before it is
+        // filled. Either way, the ACK sent tells the remote host what we expect next.
+        if packet.sequence_number() == socket.rcv.nxt {
+            let accepted_size = if receiving {
+                payload.len().min(socket.rcv.wnd as usize)
+            } else {
+                0
+            };
+            // The data is discarded after the read half is shut down.
+            if !socket.read_shutdown {
+                socket.receive_buffer.extend(&payload[..accepted_size]);
+            }
+            socket.rcv.nxt += accepted_size as u32;
+            let mut received_size = accepted_size;
+
+            // The FIN is processed only if all the preceding text has been accepted.
+            let mut fin = fin && accepted_size == payload.len();
+            if receiving && accepted_size == payload.len() && !fin {
+                let (reassembled, reassembled_fin) = socket.reassembly_queue.pop(socket.rcv.nxt);
+                if !reassembled.is_empty() {
+                    debug!(
+                        "{} : Reassembled {} bytes of data received out of order.",
+                        socket.id(),
+                        reassembled.len()
+                    );
+                }
+                if !socket.read_shutdown {
+                    socket.receive_buffer.extend(&reassembled);
+                }
+                socket.rcv.nxt += reassembled.len() as u32;
+                received_size += reassembled.len();
+                fin = reassembled_fin;
+            }
+            socket.update_receive_window();
+
+            if received_size > 0 && !socket.read_shutdown {
+                debug!(
+                    "{} : Received {} bytes of data.",
+                    socket.id(),
+                    received_size
+                );
+                self.notify_event(socket, TcpEvent::DataReadable(socket.id()))?;
+            }
+
+            if fin {
+                debug!("{} : FIN received.", socket.id());
+                socket.rcv.nxt += 1;
+                match socket.state {
+                    TcpState::SynReceived | TcpState::Established => {
+                        socket.set_state(TcpState::CloseWait)
+                    }
+                    // Simultaneous close.
+                    TcpState::FinWait1 => socket.set_state(TcpState::Closing),
+                    TcpState::FinWait2 => socket.set_state(TcpState::TimeWait),
+                    _ => {}
+                }
+                // Wake up the reader to tell it the end of the data.
+                self.notify_event(socket, TcpEvent::PeerClosed(socket.id()))?;
+            }
+        } else if receiving && socket.is_in_receive_window(packet.sequence_number()) {
+            // The part beyond the receive window is dropped along with the FIN.
+            let window_end = socket.rcv.nxt + socket.rcv.wnd;
+            let queued_size = payload
+                .len()
+                .min((window_end - packet.sequence_number()) as usize);
+            debug!(
+                "{} : Received {} bytes of data out of order.",
+                socket.id(),
+                queued_size
+            );
+            socket.reassembly_queue.insert(
+                packet.sequence_number(),
+                &payload[..queued_size],
+                fin && queued_size == payload.len(),
+            );
+        }
+
+        socket.send_tcp_packet(socket.snd.nxt, socket.rcv.nxt, TcpFlags::ACK, &[])?;
+
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown_stack() {
+            warn!("Failed to shut down the stack: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Many threads enqueue established connections on a listening socket while many others accept
+    /// them. An acceptor that misses a wakeup keeps waiting, and accept_timeout() fails.
+    /// This covers the accept queue and its wakeups only. The 3-way handshake is skipped by
+    /// inserting the established sockets directly as the receiving thread does, since processing
+    /// a SYN would send a SYN|ACK. Opening the sockets needs CAP_NET_RAW as the examples do, but
+    /// no packet is sent.
+    #[test]
+    fn accept_connections_established_concurrently() {
+        const THREADS: u16 = 8;
+        const CONNECTIONS_PER_THREAD: u16 = 25;
+        const LOCAL_PORT: u16 = 40000;
+
+        let tcp = TcpStream::new();
+        let listening_socket_id = tcp.listen(Ipv4Addr::LOCALHOST, LOCAL_PORT).unwrap();
+
+        let mut accepted = thread::scope(|s| {
+            for i in 0..THREADS {
+                let tcp = &tcp;
+                s.spawn(move || {
+                    for j in 0..CONNECTIONS_PER_THREAD {
+                        let mut connected_socket = TcpSocket::new(
+                            Ipv4Addr::LOCALHOST,
+                            LOCAL_PORT,
+                            Ipv4Addr::new(127, 0, 0, 2),
+                            TCP_PORT_RANGE_START + i * CONNECTIONS_PER_THREAD + j,
+                            Arc::clone(&tcp.event_subscribers),
+                        )
+                        .unwrap();
+                        connected_socket.set_state(TcpState::Established);
+                        connected_socket.listening_socket = Some(listening_socket_id);
+                        let connected_socket_id = connected_socket.id();
+
+                        // As the receiving thread does when the ACK of the SYN|ACK arrives.
+                        let mut socket_table = tcp.sockets.write().unwrap();
+                        socket_table.insert(connected_socket_id, connected_socket);
+                        tcp.enqueue_connection(
+                            &mut socket_table,
+                            listening_socket_id,
+                            connected_socket_id,
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+
+            let acceptors: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let tcp = &tcp;
+                    s.spawn(move || {
+                        (0..CONNECTIONS_PER_THREAD)
+                            .map(|_| {
+                                tcp.accept_timeout(listening_socket_id, Duration::from_secs(10))
+                                    .unwrap()
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            acceptors
+                .into_iter()
+                .flat_map(|acceptor| acceptor.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // Every connection is accepted exactly once.
+        accepted.sort_by_key(|socket_id| socket_id.remote_port);
+        accepted.dedup();
+        assert_eq!(accepted.len(), (THREADS * CONNECTIONS_PER_THREAD) as usize);
+        assert!(tcp.sockets.read().unwrap()[&listening_socket_id]
+            .connected_socket_queue
+            .is_empty());
+
+        // Dropping the stack would abort the connections, sending a RST for each of them.
+        tcp.sockets.write().unwrap().clear();
     }
 }
@@ -0,0 +1,310 @@
+use std::{
+    sync::{Condvar, Mutex},
+    task::Waker,
+    time::Duration,
+};
+
+use crate::error::Result;
+
+use crate::tcp::TcpEvent;
+
+/// Threads and tasks waiting for something to happen on a socket.
+/// Every notification increments a counter, and a waiter sleeps only while the counter is the
+/// same as the one it observed when it last checked the socket. Since the socket table lock is
+/// held both when the waiter checks the socket and when a notification is made, no notification
+/// is lost between the check and the sleep. Tasks register their waker instead of sleeping.
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    state: Mutex<WaitQueueState>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct WaitQueueState {
+    /// The number of notifications so far.
+    generation: u64,
+    /// The event that caused the deletion of the socket. None while the socket exists.
+    closed_by: Option<TcpEvent>,
+    /// The wakers of the tasks waiting for the next notification.
+    wakers: Vec<Waker>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of notifications so far.
+    pub fn generation(&self) -> Result<u64> {
+        let state = self.state.lock()?;
+        Ok(state.generation)
+    }
+
+    /// Get the event that caused the deletion of the socket.
+    pub fn closed_by(&self) -> Result<Option<TcpEvent>> {
+        let state = self.state.lock()?;
+        Ok(state.closed_by)
+    }
+
+    /// Register the waker of a task to be woken up by the next notification.
+    pub fn register_waker(&self, waker: &Waker) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if !state.wakers.iter().any(|w| w.will_wake(waker)) {
+            state.wakers.push(waker.clone());
+        }
+        Ok(())
+    }
+
+    /// Wake up all the waiters.
+    pub fn notify(&self) -> Result<()> {
+        let mut state = self.state.lock()?;
+        state.generation += 1;
+        self.condvar.notify_all();
+        state.wakers.drain(..).for_each(Waker::wake);
+        Ok(())
+    }
+
+    /// Wake up all the waiters for the last time since the socket has been deleted by the event.
+    pub fn close(&self, event: TcpEvent) -> Result<()> {
+        let mut state = self.state.lock()?;
+        state.generation += 1;
+        state.closed_by = Some(event);
+        self.condvar.notify_all();
+        state.wakers.drain(..).for_each(Waker::wake);
+        Ok(())
+    }
+
+    /// Block until a notification is made after the specified generation.
+    pub fn wait(&self, generation: u64) -> Result<()> {
+        let state = self.state.lock()?;
+        let _state = self
+            .condvar
+            .wait_while(state, |state| state.generation == generation)?;
+        Ok(())
+    }
+
+    /// Block until a notification is made after the specified generation or the timeout elapses.
+    /// Returns false if the timeout has elapsed.
+    pub fn wait_timeout(&self, generation: u64, timeout: Duration) -> Result<bool> {
+        let state = self.state.lock()?;
+        let (_state, result) = self
+            .condvar
+            .wait_timeout_while(state, timeout, |state| state.generation == generation)?;
+        Ok(!result.timed_out())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{self, RecvTimeoutError},
+            Arc,
+        },
+        task::Wake,
+        thread,
+    };
+
+    use super::*;
+    use crate::socket::TcpSocketId;
+
+    /// A waiter that has missed its wakeup never returns, so the test fails after this.
+    const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Run the test in another thread and fail if it hangs.
+    fn run_with_timeout(test: impl FnOnce() + Send + 'static) {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            test();
+            let _ = sender.send(());
+        });
+        match receiver.recv_timeout(TEST_TIMEOUT) {
+            // The test has panicked if the sender is dropped without sending.
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                if let Err(e) = handle.join() {
+                    std::panic::resume_unwind(e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => panic!("A wakeup has been lost."),
+        }
+    }
+
+    fn socket_id() -> TcpSocketId {
+        TcpSocketId {
+            local_address: Ipv4Addr::LOCALHOST,
+            local_port: 40000,
+            remote_address: Ipv4Addr::LOCALHOST,
+            remote_port: 50000,
+        }
+    }
+
+    /// Counts how many times it is woken up.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Threads pass a token around in turn, each waiting for the others to hand it over. The
+    /// state is checked and changed with a lock held, as the socket table is in the stack, so a
+    /// lost wakeup leaves every thread waiting.
+    #[test]
+    fn pass_token_between_threads() {
+        const THREADS: u64 = 8;
+        const ROUNDS: u64 = 2000;
+
+        run_with_timeout(|| {
+            let token = Arc::new(Mutex::new(0u64));
+            let wait_queue = Arc::new(WaitQueue::new());
+
+            let handles: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let token = Arc::clone(&token);
+                    let wait_queue = Arc::clone(&wait_queue);
+                    thread::spawn(move || loop {
+                        let mut token = token.lock().unwrap();
+                        if *token >= THREADS * ROUNDS {
+                            return;
+                        }
+                        if *token % THREADS == i {
+                            *token += 1;
+                            wait_queue.notify().unwrap();
+                            continue;
+                        }
+                        let generation = wait_queue.generation().unwrap();
+                        drop(token);
+                        wait_queue.wait(generation).unwrap();
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+
+            assert_eq!(*token.lock().unwrap(), THREADS * ROUNDS);
+        });
+    }
+
+    /// Many producers notify while many consumers wait for all the items to be produced.
+    #[test]
+    fn wake_up_all_waiters() {
+        const PRODUCERS: usize = 8;
+        const CONSUMERS: usize = 8;
+        const ITEMS: usize = 5000;
+
+        run_with_timeout(|| {
+            let produced = Arc::new(Mutex::new(0));
+            let wait_queue = Arc::new(WaitQueue::new());
+
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| {
+                    let produced = Arc::clone(&produced);
+                    let wait_queue = Arc::clone(&wait_queue);
+                    thread::spawn(move || loop {
+                        let produced = produced.lock().unwrap();
+                        if *produced == PRODUCERS * ITEMS {
+                            return;
+                        }
+                        let generation = wait_queue.generation().unwrap();
+                        drop(produced);
+                        wait_queue.wait(generation).unwrap();
+                    })
+                })
+                .collect();
+            let producers: Vec<_> = (0..PRODUCERS)
+                .map(|_| {
+                    let produced = Arc::clone(&produced);
+                    let wait_queue = Arc::clone(&wait_queue);
+                    thread::spawn(move || {
+                        for _ in 0..ITEMS {
+                            let mut produced = produced.lock().unwrap();
+                            *produced += 1;
+                            wait_queue.notify().unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for handle in producers.into_iter().chain(consumers) {
+                handle.join().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn close_wakes_up_waiters() {
+        run_with_timeout(|| {
+            let wait_queue = Arc::new(WaitQueue::new());
+            let generation = wait_queue.generation().unwrap();
+
+            let waiters: Vec<_> = (0..8)
+                .map(|_| {
+                    let wait_queue = Arc::clone(&wait_queue);
+                    thread::spawn(move || {
+                        wait_queue.wait(generation).unwrap();
+                        wait_queue.closed_by().unwrap()
+                    })
+                })
+                .collect();
+            let event = TcpEvent::ConnectionReset(socket_id());
+            wait_queue.close(event).unwrap();
+
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), Some(event));
+            }
+        });
+    }
+
+    #[test]
+    fn wait_returns_if_notified_after_generation() {
+        run_with_timeout(|| {
+            let wait_queue = WaitQueue::new();
+            let generation = wait_queue.generation().unwrap();
+            wait_queue.notify().unwrap();
+
+            // The notification has been made before waiting, so the waiter does not sleep.
+            wait_queue.wait(generation).unwrap();
+            assert!(wait_queue
+                .wait_timeout(generation, Duration::from_secs(10))
+                .unwrap());
+            assert_eq!(wait_queue.generation().unwrap(), generation + 1);
+        });
+    }
+
+    #[test]
+    fn wait_timeout_without_notification() {
+        let wait_queue = WaitQueue::new();
+        let generation = wait_queue.generation().unwrap();
+
+        assert!(!wait_queue
+            .wait_timeout(generation, Duration::from_millis(10))
+            .unwrap());
+        assert_eq!(wait_queue.closed_by().unwrap(), None);
+    }
+
+    #[test]
+    fn notify_wakes_up_registered_wakers_once() {
+        let wait_queue = WaitQueue::new();
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(Arc::clone(&counter));
+
+        // The same waker is registered only once.
+        wait_queue.register_waker(&waker).unwrap();
+        wait_queue.register_waker(&waker).unwrap();
+        wait_queue.notify().unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        // The wakers are woken up only by the next notification after the registration.
+        wait_queue.notify().unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        wait_queue.register_waker(&waker).unwrap();
+        wait_queue
+            .close(TcpEvent::ConnectionClosed(socket_id()))
+            .unwrap();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    }
+}